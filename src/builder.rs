@@ -0,0 +1,181 @@
+use std::{path::Path, time::Duration};
+
+use heed::{flags::Flags, EnvOpenOptions};
+
+use crate::{LmdbDatabase, LmdbError, MIN_DBS};
+
+/// Initial map size when none is configured.
+pub(crate) const DEFAULT_MAP_SIZE: usize = 10 * 1024 * 1024;
+
+/// Named databases in the env when none is configured. Every keyspace takes
+/// four, so this leaves room for about thirty of them.
+pub(crate) const DEFAULT_MAX_DBS: u32 = 128;
+
+/// Lease on a dequeued message when none is configured.
+pub(crate) const DEFAULT_MESSAGE_LEASE: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Flush data and metadata on every commit.
+    #[default]
+    Full,
+    /// Never flush on commit (`MDB_NOSYNC`).
+    NoSync,
+    /// Flush data but not the metadata page on commit (`MDB_NOMETASYNC`).
+    NoMetaSync,
+}
+
+/// How the map is resized when a write fails with `MDB_MAP_FULL`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MapGrowth {
+    /// Never resize; writes fail with [`LmdbError::MapFull`].
+    Fixed,
+    /// Double the map size.
+    #[default]
+    Double,
+    /// Grow the map by a fixed number of bytes.
+    Step(usize),
+}
+
+#[derive(Clone, Debug)]
+pub struct LmdbDatabaseBuilder {
+    map_size: Option<usize>,
+    max_readers: Option<u32>,
+    max_dbs: u32,
+    sync_mode: SyncMode,
+    read_only: bool,
+    map_growth: MapGrowth,
+    max_map_size: Option<usize>,
+    message_lease: Duration,
+}
+
+impl Default for LmdbDatabaseBuilder {
+    fn default() -> Self {
+        LmdbDatabaseBuilder {
+            map_size: None,
+            max_readers: None,
+            max_dbs: DEFAULT_MAX_DBS,
+            sync_mode: SyncMode::Full,
+            read_only: false,
+            map_growth: MapGrowth::Double,
+            max_map_size: None,
+            message_lease: DEFAULT_MESSAGE_LEASE,
+        }
+    }
+}
+
+impl LmdbDatabaseBuilder {
+    pub fn new() -> LmdbDatabaseBuilder {
+        LmdbDatabaseBuilder::default()
+    }
+
+    /// Size of the memory map in bytes, which bounds the size of the database.
+    /// Should be a multiple of the OS page size.
+    pub fn map_size(&mut self, size: usize) -> &mut Self {
+        self.map_size = Some(size);
+        self
+    }
+
+    pub fn max_readers(&mut self, readers: u32) -> &mut Self {
+        self.max_readers = Some(readers);
+        self
+    }
+
+    /// Maximum number of named databases in the env. Values below the number
+    /// this crate needs internally are raised to that minimum. Every keyspace
+    /// opened with [`LmdbDatabase::open_keyspace`] needs four more.
+    pub fn max_dbs(&mut self, dbs: u32) -> &mut Self {
+        self.max_dbs = dbs.max(MIN_DBS);
+        self
+    }
+
+    pub fn sync_mode(&mut self, mode: SyncMode) -> &mut Self {
+        self.sync_mode = mode;
+        self
+    }
+
+    /// Open the env with `MDB_RDONLY`. The database must already exist, and
+    /// no background expiry sweeping is done.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn map_growth(&mut self, growth: MapGrowth) -> &mut Self {
+        self.map_growth = growth;
+        self
+    }
+
+    /// Upper bound for automatic map growth. Writes fail with
+    /// [`LmdbError::MapFull`] once the map cannot grow any further.
+    pub fn max_map_size(&mut self, size: usize) -> &mut Self {
+        self.max_map_size = Some(size);
+        self
+    }
+
+    /// How long a dequeued message stays leased to its consumer. A message
+    /// that is not finished before its lease expires is delivered again.
+    pub fn message_lease(&mut self, lease: Duration) -> &mut Self {
+        self.message_lease = lease;
+        self
+    }
+
+    pub fn open(&self, path: &Path) -> Result<LmdbDatabase, LmdbError> {
+        LmdbDatabase::open_with(self.clone(), path)
+    }
+
+    pub(crate) fn initial_map_size(&self) -> usize {
+        self.map_size.unwrap_or(DEFAULT_MAP_SIZE)
+    }
+
+    pub(crate) fn message_lease_millis(&self) -> u64 {
+        self.message_lease.as_millis() as u64
+    }
+
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// The size to grow a map of `current` bytes to, or `None` if the growth
+    /// policy or the upper bound does not allow it to grow.
+    pub(crate) fn next_map_size(&self, current: usize) -> Option<usize> {
+        if self.read_only {
+            return None;
+        }
+        let next = match self.map_growth {
+            MapGrowth::Fixed => return None,
+            MapGrowth::Double => current.saturating_mul(2),
+            MapGrowth::Step(step) => current.saturating_add(step),
+        };
+        let next = self.max_map_size.map_or(next, |max| next.min(max));
+        // LMDB requires the map size to be a multiple of the page size.
+        let next = next - next % page_size::get();
+        (next > current).then_some(next)
+    }
+
+    pub(crate) fn env_options(&self, map_size: usize) -> EnvOpenOptions {
+        let mut options = EnvOpenOptions::new();
+        options.max_dbs(self.max_dbs);
+        options.map_size(map_size);
+        if let Some(readers) = self.max_readers {
+            options.max_readers(readers);
+        }
+        // SAFETY: none of these flags change the memory layout heed relies on;
+        // they only relax durability or forbid writes.
+        unsafe {
+            match self.sync_mode {
+                SyncMode::Full => {}
+                SyncMode::NoSync => {
+                    options.flag(Flags::MdbNoSync);
+                }
+                SyncMode::NoMetaSync => {
+                    options.flag(Flags::MdbNoMetaSync);
+                }
+            }
+            if self.read_only {
+                options.flag(Flags::MdbRdOnly);
+            }
+        }
+        options
+    }
+}
@@ -0,0 +1,105 @@
+use heed::MdbError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LmdbError {
+    #[error("Database is corrupted: {0}")]
+    Corrupted(String),
+    #[error("Database map is full")]
+    MapFull,
+    #[error("{0}")]
+    LimitExceeded(String),
+    #[error("Check failed")]
+    CheckFailed,
+    #[error("Database is closed")]
+    Closed,
+    #[error("Database is opened read-only")]
+    ReadOnly,
+    #[error("Database '{0}' does not exist in the environment")]
+    MissingDatabase(String),
+    #[error(
+        "Too many keyspaces for the environment's max_dbs; raise LmdbDatabaseBuilder::max_dbs"
+    )]
+    TooManyKeyspaces,
+    #[error("Invalid keyspace name {0:?}: it must be non-empty and contain no '/' or NUL")]
+    InvalidKeyspaceName(String),
+    #[error("Database format version {found} is newer than the supported version {supported}")]
+    FormatTooNew { found: u64, supported: u64 },
+    #[error("Database format version {0} needs a migration, which cannot run in read-only mode")]
+    MigrationRequired(u64),
+    #[error("Failed to perform '{0}' mutation on a non-U64 operand")]
+    NonU64Operand(&'static str),
+    #[error("Failed to perform '{0}' mutation on a non-U64 value in the database")]
+    NonU64Value(&'static str),
+    #[error("Unsupported mutation kind: {0}")]
+    UnsupportedMutation(&'static str),
+    #[error("Message payload was already taken")]
+    PayloadTaken,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("LMDB error: {0}")]
+    Lmdb(MdbError),
+    #[error("Background task failed: {0}")]
+    Background(String),
+    #[error("{0}")]
+    Heed(String),
+}
+
+impl From<heed::Error> for LmdbError {
+    fn from(e: heed::Error) -> Self {
+        match e {
+            heed::Error::Io(e) => LmdbError::Io(e),
+            heed::Error::Mdb(MdbError::MapFull) => LmdbError::MapFull,
+            heed::Error::Mdb(
+                e @ (MdbError::Corrupted
+                | MdbError::PageNotFound
+                | MdbError::Invalid
+                | MdbError::Panic),
+            ) => LmdbError::Corrupted(e.to_string()),
+            heed::Error::Mdb(e) => LmdbError::Lmdb(e),
+            heed::Error::Decoding(e) => match e.downcast::<LmdbError>() {
+                Ok(e) => *e,
+                Err(e) => LmdbError::Corrupted(e.to_string()),
+            },
+            heed::Error::DatabaseClosing => LmdbError::Closed,
+            e @ (heed::Error::Encoding(_) | heed::Error::InvalidDatabaseTyping) => {
+                LmdbError::Heed(e.to_string())
+            }
+        }
+    }
+}
+
+impl From<tokio::task::JoinError> for LmdbError {
+    fn from(e: tokio::task::JoinError) -> Self {
+        LmdbError::Background(e.to_string())
+    }
+}
+
+impl Clone for LmdbError {
+    fn clone(&self) -> Self {
+        match self {
+            LmdbError::Corrupted(msg) => LmdbError::Corrupted(msg.clone()),
+            LmdbError::MapFull => LmdbError::MapFull,
+            LmdbError::LimitExceeded(msg) => LmdbError::LimitExceeded(msg.clone()),
+            LmdbError::CheckFailed => LmdbError::CheckFailed,
+            LmdbError::Closed => LmdbError::Closed,
+            LmdbError::ReadOnly => LmdbError::ReadOnly,
+            LmdbError::MissingDatabase(name) => LmdbError::MissingDatabase(name.clone()),
+            LmdbError::TooManyKeyspaces => LmdbError::TooManyKeyspaces,
+            LmdbError::InvalidKeyspaceName(name) => LmdbError::InvalidKeyspaceName(name.clone()),
+            LmdbError::FormatTooNew { found, supported } => LmdbError::FormatTooNew {
+                found: *found,
+                supported: *supported,
+            },
+            LmdbError::MigrationRequired(version) => LmdbError::MigrationRequired(*version),
+            LmdbError::NonU64Operand(op) => LmdbError::NonU64Operand(op),
+            LmdbError::NonU64Value(op) => LmdbError::NonU64Value(op),
+            LmdbError::UnsupportedMutation(kind) => LmdbError::UnsupportedMutation(kind),
+            LmdbError::PayloadTaken => LmdbError::PayloadTaken,
+            // io::Error is not Clone, so only its kind and message survive.
+            LmdbError::Io(e) => LmdbError::Io(std::io::Error::new(e.kind(), e.to_string())),
+            LmdbError::Lmdb(e) => LmdbError::Lmdb(*e),
+            LmdbError::Background(msg) => LmdbError::Background(msg.clone()),
+            LmdbError::Heed(msg) => LmdbError::Heed(msg.clone()),
+        }
+    }
+}
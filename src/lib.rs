@@ -1,102 +1,1052 @@
-use std::{borrow::Cow, path::Path};
+mod builder;
+mod error;
+mod limits;
+mod migrate;
+mod queue;
+#[cfg(test)]
+mod test_util;
+mod watch;
+mod writer;
+
+use std::{
+    borrow::Cow,
+    ops::{Bound, Deref},
+    path::Path,
+    pin::pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex, RwLock, RwLockReadGuard,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use anyhow::Error;
 use async_trait::async_trait;
+use futures::{
+    stream::{self, BoxStream},
+    TryStreamExt,
+};
 
 use denokv_proto::{
-    AtomicWrite, CommitResult, KvEntry, KvValue, QueueMessageHandle, ReadRange, ReadRangeOutput,
-    SnapshotReadOptions, WatchStream,
+    AtomicWrite, CommitResult, KvEntry, KvValue, MutationKind, ReadRange, ReadRangeOutput,
+    SnapshotReadOptions, Versionstamp, WatchKeyOutput, WatchStream,
+};
+use heed::{
+    types::{ByteSlice, OwnedType, SerdeBincode, Str, Unit},
+    BytesDecode, BytesEncode, MdbError, RoTxn, RwTxn,
 };
-use heed::{BytesDecode, BytesEncode};
+use tokio::sync::Notify;
 
-pub struct LmdbMessageHandle;
+/// Named databases the default keyspace needs, with room to spare.
+const MIN_DBS: u32 = 8;
+const VERSION_KEY: &str = "version";
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+const EXPIRY_SWEEP_BATCH_SIZE: usize = 1000;
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub use builder::{LmdbDatabaseBuilder, MapGrowth, SyncMode};
+pub use error::LmdbError;
+use queue::{Dequeue, Leases, QueueMessage};
+pub use queue::{
+    LmdbMessageHandle, QueueMessageId, QueueMessageInfo, QueueMessageState, DEFAULT_QUEUE,
+};
+use watch::{CommitPoller, WatchHub};
+use writer::Writer;
 
 #[derive(Clone)]
 pub struct LmdbDatabase {
+    store: Arc<StoreSlot>,
+    /// Index of this handle's keyspace in the store.
+    keyspace: usize,
+    config: Arc<LmdbDatabaseBuilder>,
+    watchers: Arc<WatchHub>,
+    sweeper: Arc<ExpirySweeper>,
+    poller: Arc<CommitPoller>,
+    /// Woken whenever a message may have become due earlier than expected.
+    queue_waker: Arc<Notify>,
+    writer: Arc<Writer>,
+    closed: Arc<RwLock<bool>>,
+    /// The closed flag of each keyspace's open handle, by keyspace index.
+    keyspace_flags: Arc<Mutex<Vec<Arc<RwLock<bool>>>>>,
+    /// Keyspace handles that are not closed yet. The env is shut down when
+    /// the last one closes.
+    open_handles: Arc<AtomicUsize>,
+    leases: Arc<Leases>,
+}
+
+/// One store per keyspace, the default keyspace first. This is the only place
+/// the env is held, so it can be closed and reopened with a larger map.
+type StoreSlot = RwLock<Option<Vec<Store>>>;
+
+/// The heed env and the databases of one keyspace.
+struct Store {
     env: heed::Env,
+    map_size: usize,
+    /// `None` for the default keyspace.
+    keyspace: Option<String>,
     db: heed::Database<LmdbDKvKey, LmdbDKvValue>,
+    expiry: heed::Database<ByteSlice, Unit>,
+    meta: heed::Database<Str, OwnedType<u64>>,
+    queue: heed::Database<ByteSlice, SerdeBincode<QueueMessage>>,
+    queue_running: heed::Database<ByteSlice, SerdeBincode<QueueMessage>>,
+}
+
+/// Derefs to the store of one keyspace.
+struct StoreGuard<'a> {
+    stores: RwLockReadGuard<'a, Option<Vec<Store>>>,
+    keyspace: usize,
+}
+
+impl StoreGuard<'_> {
+    fn all(&self) -> &[Store] {
+        self.stores
+            .as_deref()
+            .expect("StoreGuard is only built for an open store")
+    }
+}
+
+impl Deref for StoreGuard<'_> {
+    type Target = Store;
+
+    fn deref(&self) -> &Store {
+        &self.all()[self.keyspace]
+    }
+}
+
+fn read_store(store: &StoreSlot, keyspace: usize) -> Result<StoreGuard<'_>, LmdbError> {
+    let stores = store.read().unwrap();
+    if stores.is_none() {
+        return Err(LmdbError::Closed);
+    }
+    Ok(StoreGuard { stores, keyspace })
+}
+
+/// Codec for keys in `kv`. Keys are encoded as-is and decoded as slices
+/// borrowed from the transaction.
+struct LmdbDKvKey;
+
+/// Codec for values in `kv`, encoded as `[tag][versionstamp][expire_at][payload]`.
+struct LmdbDKvValue;
+
+/// A value in `kv`. The payload borrows from the transaction it was read in
+/// or from the write it is about to be stored by, so it is only copied once.
+struct KvRecord<'a> {
+    value: KvValueRef<'a>,
+    versionstamp: Versionstamp,
+    expire_at: Option<u64>,
+}
+
+#[derive(Clone, Copy)]
+enum KvValueRef<'a> {
+    V8(&'a [u8]),
+    Bytes(&'a [u8]),
+    U64(u64),
+}
+
+impl KvRecord<'_> {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expire_at.is_some_and(|expire_at| expire_at <= now)
+    }
 }
 
-struct LmdbDKvKey(Vec<u8>);
-struct LmdbDKvValue(KvValue);
+impl KvValueRef<'_> {
+    fn to_owned(self) -> KvValue {
+        match self {
+            KvValueRef::V8(bytes) => KvValue::V8(bytes.to_vec()),
+            KvValueRef::Bytes(bytes) => KvValue::Bytes(bytes.to_vec()),
+            KvValueRef::U64(n) => KvValue::U64(n),
+        }
+    }
+}
+
+impl<'a> From<&'a KvValue> for KvValueRef<'a> {
+    fn from(value: &'a KvValue) -> Self {
+        match value {
+            KvValue::V8(bytes) => KvValueRef::V8(bytes),
+            KvValue::Bytes(bytes) => KvValueRef::Bytes(bytes),
+            KvValue::U64(n) => KvValueRef::U64(*n),
+        }
+    }
+}
 
 impl<'a> BytesDecode<'a> for LmdbDKvKey {
-    type DItem = LmdbDKvKey;
+    type DItem = &'a [u8];
 
-    fn bytes_decode(bytes: &[u8]) -> Result<Self::DItem, Box<dyn std::error::Error>> {
-        let mut vec = Vec::<u8>::new();
-        vec.extend_from_slice(bytes);
-        Ok(LmdbDKvKey(vec))
+    fn bytes_decode(bytes: &'a [u8]) -> Result<Self::DItem, Box<dyn std::error::Error>> {
+        Ok(bytes)
     }
 }
 
-impl BytesEncode<'_> for LmdbDKvKey {
-    type EItem = LmdbDKvKey;
+impl<'a> BytesEncode<'a> for LmdbDKvKey {
+    type EItem = [u8];
 
-    fn bytes_encode(item: &Self::EItem) -> Result<Cow<[u8]>, Box<dyn std::error::Error>> {
-        Ok(Cow::Owned(item.0.clone()))
+    fn bytes_encode(item: &'a Self::EItem) -> Result<Cow<'a, [u8]>, Box<dyn std::error::Error>> {
+        Ok(Cow::Borrowed(item))
     }
 }
 
-impl BytesDecode<'_> for LmdbDKvValue {
-    type DItem = LmdbDKvValue;
-    fn bytes_decode(bytes: &[u8]) -> Result<Self::DItem, Box<dyn std::error::Error>> {
-        let mut vec = Vec::<u8>::new();
-        vec.extend_from_slice(bytes);
-        let (_, list) = vec.split_at(1);
-        if vec[0] == 0 {
-            Ok(LmdbDKvValue(KvValue::U64(u64::from_le_bytes(
-                list.try_into()
-                    .expect("Wrong number of bytes for LmdbDKvValue"),
-            ))))
-        } else if vec[0] == 1 {
-            Ok(LmdbDKvValue(KvValue::Bytes(list.to_owned())))
-        } else {
-            Ok(LmdbDKvValue(KvValue::V8(list.to_owned())))
+impl<'a> BytesDecode<'a> for LmdbDKvValue {
+    type DItem = KvRecord<'a>;
+
+    fn bytes_decode(bytes: &'a [u8]) -> Result<Self::DItem, Box<dyn std::error::Error>> {
+        if bytes.len() < 19 {
+            return Err(LmdbError::Corrupted(format!(
+                "value header is {} bytes, expected at least 19",
+                bytes.len()
+            ))
+            .into());
         }
+        let (tag, rest) = bytes.split_at(1);
+        let (versionstamp, rest) = rest.split_at(10);
+        let (expire_at, list) = rest.split_at(8);
+        let value = match tag[0] {
+            0 => KvValueRef::U64(u64::from_le_bytes(list.try_into().map_err(|_| {
+                LmdbError::Corrupted(format!("U64 value is {} bytes, expected 8", list.len()))
+            })?)),
+            1 => KvValueRef::Bytes(list),
+            2 => KvValueRef::V8(list),
+            tag => {
+                return Err(LmdbError::Corrupted(format!("unknown value tag {}", tag)).into());
+            }
+        };
+        Ok(KvRecord {
+            value,
+            versionstamp: versionstamp.try_into()?,
+            expire_at: match u64::from_be_bytes(expire_at.try_into()?) {
+                0 => None,
+                expire_at => Some(expire_at),
+            },
+        })
     }
 }
 
 impl<'a> BytesEncode<'a> for LmdbDKvValue {
-    type EItem = LmdbDKvValue;
-
-    fn bytes_encode(item: &Self::EItem) -> Result<Cow<[u8]>, Box<dyn std::error::Error>> {
-        let mut res = vec![match &item.0 {
-            KvValue::V8(_) => 2u8,
-            KvValue::Bytes(_) => 1u8,
-            _ => 0u8,
-        }];
-
-        let contents = match &item.0 {
-            KvValue::V8(val) | KvValue::Bytes(val) => val.to_owned(),
-            KvValue::U64(val) => val.to_le_bytes().to_vec(),
+    type EItem = KvRecord<'a>;
+
+    fn bytes_encode(item: &'a Self::EItem) -> Result<Cow<'a, [u8]>, Box<dyn std::error::Error>> {
+        let n;
+        let (tag, contents) = match item.value {
+            KvValueRef::V8(val) => (2u8, val),
+            KvValueRef::Bytes(val) => (1u8, val),
+            KvValueRef::U64(val) => {
+                n = val.to_le_bytes();
+                (0u8, &n[..])
+            }
         };
 
-        res.extend(contents);
+        let mut res = Vec::with_capacity(19 + contents.len());
+        res.push(tag);
+        res.extend_from_slice(&item.versionstamp);
+        res.extend_from_slice(&item.expire_at.unwrap_or(0).to_be_bytes());
+        res.extend_from_slice(contents);
 
         Ok(Cow::Owned(res))
     }
 }
 
 impl LmdbDatabase {
-    pub fn new(path: &Path) -> Result<LmdbDatabase, Error> {
-        let options = heed::EnvOpenOptions::new();
-        let env = options.open(path).map_err(|e| Error::msg(e.to_string()))?;
-        let db = env
-            .open_database::<LmdbDKvKey, LmdbDKvValue>(None)
-            .map_err(|e| Error::msg(e.to_string()))?
-            .expect("Database was None while opening!");
-        Ok(LmdbDatabase { env, db })
+    pub fn new(path: &Path) -> Result<LmdbDatabase, LmdbError> {
+        LmdbDatabaseBuilder::new().open(path)
+    }
+
+    pub fn builder() -> LmdbDatabaseBuilder {
+        LmdbDatabaseBuilder::new()
+    }
+
+    /// Open an existing database with `MDB_RDONLY`, so that several processes
+    /// can read it alongside the one that writes to it. Reads and watches
+    /// work as usual; writes and dequeues fail with [`LmdbError::ReadOnly`].
+    pub fn open_read_only(path: &Path) -> Result<LmdbDatabase, LmdbError> {
+        LmdbDatabaseBuilder::new().read_only(true).open(path)
+    }
+
+    fn open_with(config: LmdbDatabaseBuilder, path: &Path) -> Result<LmdbDatabase, LmdbError> {
+        // Messages left running by a process that exited without finishing
+        // them are requeued by the sweeper once their lease expires; other
+        // processes sharing the env may still hold live leases.
+        let stores = Store::open(&config, path, config.initial_map_size(), &[None])?;
+        let store = Arc::new(RwLock::new(Some(stores)));
+        let watchers = Arc::new(WatchHub::default());
+        let queue_waker = Arc::new(Notify::new());
+        let leases = Arc::new(Leases::default());
+        let (sweeper, writer) = if config.is_read_only() {
+            (ExpirySweeper::default(), Writer::default())
+        } else {
+            let sweeper = ExpirySweeper::spawn(
+                store.clone(),
+                watchers.clone(),
+                queue_waker.clone(),
+                leases.clone(),
+            );
+            (sweeper, Writer::spawn())
+        };
+        let poller = CommitPoller::spawn(store.clone(), watchers.clone(), queue_waker.clone());
+        let closed = Arc::new(RwLock::new(false));
+        Ok(LmdbDatabase {
+            store,
+            keyspace: 0,
+            config: Arc::new(config),
+            watchers,
+            sweeper: Arc::new(sweeper),
+            poller: Arc::new(poller),
+            queue_waker,
+            writer: Arc::new(writer),
+            keyspace_flags: Arc::new(Mutex::new(vec![closed.clone()])),
+            closed,
+            open_handles: Arc::new(AtomicUsize::new(1)),
+            leases,
+        })
+    }
+
+    /// Open a named keyspace in the same env. It shares the env's map, writer
+    /// thread and commit versions with every other keyspace, but none of
+    /// their keys or queues. Each keyspace takes four named databases, which
+    /// count towards [`LmdbDatabaseBuilder::max_dbs`].
+    ///
+    /// Opening a keyspace that already has an open handle returns a clone of
+    /// that handle, so closing either one closes both. The env stays open
+    /// until this database and every keyspace opened from it have been closed.
+    ///
+    /// Names must be non-empty and may not contain `/` or NUL, which LMDB and
+    /// the database naming scheme reserve.
+    pub fn open_keyspace(&self, name: &str) -> Result<LmdbDatabase, LmdbError> {
+        if name.is_empty() || name.contains(['/', '\0']) {
+            return Err(LmdbError::InvalidKeyspaceName(name.to_owned()));
+        }
+        let _open = self.ensure_open()?;
+        let mut slot = self.store.write().unwrap();
+        let stores = slot.as_mut().ok_or(LmdbError::Closed)?;
+        let keyspace = match stores
+            .iter()
+            .position(|store| store.keyspace.as_deref() == Some(name))
+        {
+            Some(keyspace) => keyspace,
+            None => {
+                let read_only = self.config.is_read_only();
+                let store = Store::from_env(
+                    stores[0].env.clone(),
+                    Some(name.to_owned()),
+                    read_only,
+                    stores[0].map_size,
+                )
+                .map_err(|e| match e {
+                    LmdbError::Lmdb(MdbError::DbsFull) => LmdbError::TooManyKeyspaces,
+                    e => e,
+                })?;
+                stores.push(store);
+                stores.len() - 1
+            }
+        };
+
+        let mut flags = self.keyspace_flags.lock().unwrap();
+        if keyspace == flags.len() {
+            flags.push(Arc::new(RwLock::new(true)));
+        }
+        // `self` may be a handle to this keyspace, whose flag is already held.
+        let open = Arc::ptr_eq(&flags[keyspace], &self.closed) || !*flags[keyspace].read().unwrap();
+        if !open {
+            flags[keyspace] = Arc::new(RwLock::new(false));
+            self.open_handles.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(LmdbDatabase {
+            keyspace,
+            closed: flags[keyspace].clone(),
+            ..self.clone()
+        })
+    }
+
+    fn store(&self) -> Result<StoreGuard<'_>, LmdbError> {
+        read_store(&self.store, self.keyspace)
+    }
+
+    /// Close the env and reopen it with a larger map. Taking the store's write
+    /// lock waits for every open transaction to finish first.
+    fn grow_map(&self, full_at: usize) -> Result<(), LmdbError> {
+        let mut slot = self.store.write().unwrap();
+        let Some(stores) = slot.take() else {
+            return Err(LmdbError::Closed);
+        };
+        if stores[0].map_size > full_at {
+            // Another writer already grew the map while we were waiting.
+            *slot = Some(stores);
+            return Ok(());
+        }
+        let Some(map_size) = self.config.next_map_size(full_at) else {
+            *slot = Some(stores);
+            return Err(LmdbError::MapFull);
+        };
+
+        self.reopen(&mut slot, stores, map_size)
+    }
+
+    /// Reopen the env after another process grew the map beyond ours. LMDB
+    /// extends the map to cover the pages in use when the env is opened.
+    fn remap(&self) -> Result<(), LmdbError> {
+        let mut slot = self.store.write().unwrap();
+        let Some(stores) = slot.take() else {
+            return Err(LmdbError::Closed);
+        };
+        if stores[0].env.read_txn().is_ok() {
+            // Another reader already reopened the env while we were waiting.
+            *slot = Some(stores);
+            return Ok(());
+        }
+
+        let map_size = stores[0].map_size;
+        self.reopen(&mut slot, stores, map_size)
+    }
+
+    /// Close the env and open it again with the same keyspaces. If that fails,
+    /// the env is reopened with the map size it had so that the handle stays
+    /// usable, and the error is returned.
+    fn reopen(
+        &self,
+        slot: &mut Option<Vec<Store>>,
+        stores: Vec<Store>,
+        map_size: usize,
+    ) -> Result<(), LmdbError> {
+        let (path, previous_size) = (stores[0].env.path().to_path_buf(), stores[0].map_size);
+        let keyspaces = stores
+            .iter()
+            .map(|store| store.keyspace.clone())
+            .collect::<Vec<_>>();
+        let closing = stores[0].env.clone().prepare_for_closing();
+        drop(stores);
+        closing.wait();
+        match Store::open(&self.config, &path, map_size, &keyspaces) {
+            Ok(stores) => {
+                *slot = Some(stores);
+                Ok(())
+            }
+            Err(e) => {
+                *slot = Some(Store::open(&self.config, &path, previous_size, &keyspaces)?);
+                Err(e)
+            }
+        }
+    }
+
+    /// Run a read against the store, reopening the env and retrying it
+    /// whenever it fails with `MDB_MAP_RESIZED`.
+    fn with_store<T>(
+        &self,
+        mut read: impl FnMut(&StoreGuard) -> Result<T, LmdbError>,
+    ) -> Result<T, LmdbError> {
+        loop {
+            let store = self.store()?;
+            match read(&store) {
+                Err(LmdbError::Lmdb(MdbError::MapResized)) => {
+                    drop(store);
+                    self.remap()?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn ensure_writable(&self) -> Result<(), LmdbError> {
+        if self.config.is_read_only() {
+            return Err(LmdbError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn ensure_open(&self) -> Result<RwLockReadGuard<'_, bool>, LmdbError> {
+        let closed = self.closed.read().unwrap();
+        if *closed {
+            return Err(LmdbError::Closed);
+        }
+        Ok(closed)
+    }
+
+    fn read_ranges(&self, requests: &[ReadRange]) -> Result<Vec<ReadRangeOutput>, LmdbError> {
+        let _open = self.ensure_open()?;
+        self.with_store(|store| {
+            let mut res = Vec::<ReadRangeOutput>::new();
+            let now = now_millis();
+            let txn = store.env.read_txn()?;
+            for req in requests {
+                let range = &(
+                    Bound::Included(req.start.as_slice()),
+                    Bound::Excluded(req.end.as_slice()),
+                );
+
+                let results: Box<dyn Iterator<Item = heed::Result<(&[u8], KvRecord)>>> =
+                    if req.reverse {
+                        Box::new(store.db.rev_range(&txn, range)?)
+                    } else {
+                        Box::new(store.db.range(&txn, range)?)
+                    };
+
+                res.push(ReadRangeOutput {
+                    entries: results
+                        .filter(|entry| !matches!(entry, Ok((_, v)) if v.is_expired(now)))
+                        .take(req.limit.get() as usize)
+                        .map(|entry| {
+                            entry.map(|(k, v)| KvEntry {
+                                key: k.to_vec(),
+                                value: v.value.to_owned(),
+                                versionstamp: v.versionstamp,
+                            })
+                        })
+                        .collect::<Result<_, _>>()?,
+                });
+            }
+
+            Ok(res)
+        })
+    }
+
+    /// Run a write transaction, growing the map and retrying it whenever it
+    /// fails with `MDB_MAP_FULL`.
+    fn with_map_growth<T>(
+        &self,
+        mut write: impl FnMut(&StoreGuard) -> Result<T, LmdbError>,
+    ) -> Result<T, LmdbError> {
+        loop {
+            let store = self.store()?;
+            match write(&store) {
+                Err(LmdbError::MapFull) => {
+                    let full_at = store.map_size;
+                    drop(store);
+                    self.grow_map(full_at)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Commit a batch of writes in one transaction. Each write runs in its own
+    /// nested transaction, so a failed check only rolls back that write.
+    /// Each write is paired with its keyspace and the queue its enqueues go
+    /// to.
+    fn commit_batch(
+        &self,
+        writes: &[(usize, String, AtomicWrite)],
+    ) -> Vec<Result<CommitResult, LmdbError>> {
+        let results = self
+            .ensure_open()
+            .and_then(|_open| self.with_map_growth(|store| self.try_commit_batch(store, writes)));
+        match results {
+            Ok(results) => results,
+            Err(e) => writes.iter().map(|_| Err(e.clone())).collect(),
+        }
+    }
+
+    fn try_commit_batch(
+        &self,
+        stores: &StoreGuard,
+        writes: &[(usize, String, AtomicWrite)],
+    ) -> Result<Vec<Result<CommitResult, LmdbError>>, LmdbError> {
+        let mut txn = stores.env.write_txn()?;
+        let version = stores.next_version(&mut txn)?;
+        let now = now_millis();
+
+        let mut results = Vec::with_capacity(writes.len());
+        let mut changed_keys = Vec::new();
+        let mut enqueued = false;
+        for (index, (keyspace, queue, write)) in writes.iter().enumerate() {
+            let store = &stores.all()[*keyspace];
+            let versionstamp = versionstamp(version, index as u16);
+            let mut nested = store.env.nested_write_txn(&mut txn)?;
+            match store.apply_write(&mut nested, write, queue, versionstamp, now) {
+                Ok(keys) => {
+                    nested.commit()?;
+                    changed_keys.extend(keys.into_iter().map(|key| (*keyspace, key)));
+                    enqueued |= !write.enqueues.is_empty();
+                    results.push(Ok(CommitResult { versionstamp }));
+                }
+                // Growing the map retries the whole batch.
+                Err(LmdbError::MapFull) => return Err(LmdbError::MapFull),
+                Err(e) => results.push(Err(e)),
+            }
+        }
+
+        if results.iter().any(Result::is_ok) {
+            txn.commit()?;
+            for (keyspace, key) in &changed_keys {
+                self.watchers.notify(*keyspace, [key.as_slice()]);
+            }
+            if enqueued {
+                self.queue_waker.notify_waiters();
+            }
+        }
+        Ok(results)
     }
 }
 
-#[async_trait(?Send)]
-impl QueueMessageHandle for LmdbMessageHandle {
-    async fn take_payload(&mut self) -> Result<Vec<u8>, anyhow::Error> {
-        todo!()
+impl Store {
+    /// Open the env and the stores of the given keyspaces.
+    fn open(
+        config: &LmdbDatabaseBuilder,
+        path: &Path,
+        map_size: usize,
+        keyspaces: &[Option<String>],
+    ) -> Result<Vec<Store>, LmdbError> {
+        let env = config.env_options(map_size).open(path)?;
+        let read_only = config.is_read_only();
+        migrate::migrate(&env, read_only)
+            .and_then(|()| {
+                keyspaces
+                    .iter()
+                    .map(|keyspace| {
+                        Store::from_env(env.clone(), keyspace.clone(), read_only, map_size)
+                    })
+                    .collect()
+            })
+            .inspect_err(|_| {
+                // heed caches every env it opens; drop it so that a later open
+                // of the same path starts from scratch.
+                env.prepare_for_closing();
+            })
+    }
+
+    /// The default keyspace uses the bare database names, so that files
+    /// written before keyspaces existed open unchanged. Every keyspace shares
+    /// the env's "meta" database and with it the commit version.
+    ///
+    /// The databases are created in one transaction, so running out of
+    /// `max_dbs` partway leaves none of them behind.
+    fn from_env(
+        env: heed::Env,
+        keyspace: Option<String>,
+        read_only: bool,
+        map_size: usize,
+    ) -> Result<Store, LmdbError> {
+        let name = |db: &str| match &keyspace {
+            Some(keyspace) => format!("{keyspace}/{db}"),
+            None => db.to_owned(),
+        };
+        let mut txn = if read_only {
+            None
+        } else {
+            Some(env.write_txn()?)
+        };
+        let db = open_or_create(&env, txn.as_mut(), &name("kv"))?;
+        let expiry = open_or_create(&env, txn.as_mut(), &name("expiry"))?;
+        let meta = open_or_create(&env, txn.as_mut(), "meta")?;
+        let queue = open_or_create(&env, txn.as_mut(), &name("queue"))?;
+        let queue_running = open_or_create(&env, txn.as_mut(), &name("queue_running"))?;
+        txn.map(RwTxn::commit).transpose()?;
+        Ok(Store {
+            db,
+            expiry,
+            meta,
+            queue,
+            queue_running,
+            env,
+            map_size,
+            keyspace,
+        })
+    }
+
+    /// Apply one write, returning the keys it changed.
+    fn apply_write(
+        &self,
+        txn: &mut RwTxn,
+        write: &AtomicWrite,
+        queue: &str,
+        versionstamp: Versionstamp,
+        now: u64,
+    ) -> Result<Vec<Vec<u8>>, LmdbError> {
+        for check in &write.checks {
+            let current = self.get_live(txn, &check.key, now)?.map(|v| v.versionstamp);
+            if current != check.versionstamp {
+                return Err(LmdbError::CheckFailed);
+            }
+        }
+
+        let mut changed_keys = Vec::with_capacity(write.mutations.len());
+        for mutation in &write.mutations {
+            let key = mutation.key.as_slice();
+            // Zero is reserved for "no expiry" in the value encoding.
+            let expire_at = mutation
+                .expire_at
+                .map(|expire_at| expire_at.timestamp_millis().max(1) as u64);
+            match &mutation.kind {
+                MutationKind::Set(value) => self.put_entry(
+                    txn,
+                    key,
+                    &KvRecord {
+                        value: value.into(),
+                        versionstamp,
+                        expire_at,
+                    },
+                )?,
+                MutationKind::Delete => self.delete_entry(txn, key)?,
+                MutationKind::Sum { value, .. } => self.mutate_le64(
+                    txn,
+                    key,
+                    "sum",
+                    value,
+                    versionstamp,
+                    expire_at,
+                    now,
+                    u64::wrapping_add,
+                )?,
+                MutationKind::Min(value) => self.mutate_le64(
+                    txn,
+                    key,
+                    "min",
+                    value,
+                    versionstamp,
+                    expire_at,
+                    now,
+                    u64::min,
+                )?,
+                MutationKind::Max(value) => self.mutate_le64(
+                    txn,
+                    key,
+                    "max",
+                    value,
+                    versionstamp,
+                    expire_at,
+                    now,
+                    u64::max,
+                )?,
+                MutationKind::SetSuffixVersionstampedKey(_) => {
+                    return Err(LmdbError::UnsupportedMutation(
+                        "set_suffix_versionstamped_key",
+                    ));
+                }
+            }
+            changed_keys.push(key.to_vec());
+        }
+
+        self.enqueue(txn, queue, versionstamp, &write.enqueues)?;
+        Ok(changed_keys)
+    }
+
+    /// The version of the last commit, made by this or any other process.
+    fn committed_version(&self) -> Result<u64, heed::Error> {
+        let txn = self.env.read_txn()?;
+        Ok(self.meta.get(&txn, VERSION_KEY)?.unwrap_or(0))
+    }
+
+    fn next_version(&self, txn: &mut RwTxn) -> Result<u64, heed::Error> {
+        let version = self.meta.get(txn, VERSION_KEY)?.unwrap_or(0) + 1;
+        self.meta.put(txn, VERSION_KEY, &version)?;
+        Ok(version)
+    }
+
+    fn get_live<'t>(
+        &self,
+        txn: &'t RoTxn,
+        key: &[u8],
+        now: u64,
+    ) -> Result<Option<KvRecord<'t>>, heed::Error> {
+        Ok(self.db.get(txn, key)?.filter(|v| !v.is_expired(now)))
+    }
+
+    fn put_entry(&self, txn: &mut RwTxn, key: &[u8], value: &KvRecord) -> Result<(), heed::Error> {
+        self.remove_expiry(txn, key)?;
+        if let Some(expire_at) = value.expire_at {
+            self.expiry.put(txn, &expiry_key(expire_at, key), &())?;
+        }
+        self.db.put(txn, key, value)
+    }
+
+    fn delete_entry(&self, txn: &mut RwTxn, key: &[u8]) -> Result<(), heed::Error> {
+        self.remove_expiry(txn, key)?;
+        self.db.delete(txn, key)?;
+        Ok(())
     }
-    async fn finish(&self, success: bool) -> Result<(), anyhow::Error> {
-        todo!()
+
+    fn remove_expiry(&self, txn: &mut RwTxn, key: &[u8]) -> Result<(), heed::Error> {
+        if let Some(expire_at) = self.db.get(txn, key)?.and_then(|v| v.expire_at) {
+            self.expiry.delete(txn, &expiry_key(expire_at, key))?;
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn mutate_le64(
+        &self,
+        txn: &mut RwTxn,
+        key: &[u8],
+        op: &'static str,
+        operand: &KvValue,
+        versionstamp: Versionstamp,
+        expire_at: Option<u64>,
+        now: u64,
+        mutate: impl FnOnce(u64, u64) -> u64,
+    ) -> Result<(), LmdbError> {
+        let &KvValue::U64(operand) = operand else {
+            return Err(LmdbError::NonU64Operand(op));
+        };
+
+        let value = match self.get_live(txn, key, now)? {
+            None => operand,
+            Some(KvRecord {
+                value: KvValueRef::U64(current),
+                ..
+            }) => mutate(current, operand),
+            Some(_) => return Err(LmdbError::NonU64Value(op)),
+        };
+
+        self.put_entry(
+            txn,
+            key,
+            &KvRecord {
+                value: KvValueRef::U64(value),
+                versionstamp,
+                expire_at,
+            },
+        )?;
+        Ok(())
+    }
+}
+
+/// Deletes expired keys and requeues messages whose lease has expired.
+#[derive(Default)]
+struct ExpirySweeper {
+    stop: Mutex<Option<mpsc::Sender<()>>>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl ExpirySweeper {
+    fn spawn(
+        store: Arc<StoreSlot>,
+        watchers: Arc<WatchHub>,
+        queue_waker: Arc<Notify>,
+        leases: Arc<Leases>,
+    ) -> ExpirySweeper {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(EXPIRY_SWEEP_INTERVAL) {
+                // A failed sweep is simply retried on the next tick.
+                if let Ok(stores) = read_store(&store, 0) {
+                    let now = now_millis();
+                    for (keyspace, store) in stores.all().iter().enumerate() {
+                        let _ = sweep_expired(store, keyspace, &watchers, now);
+                        if let Ok(requeued) = store.expire_leases(now) {
+                            watchers.notify(
+                                keyspace,
+                                requeued.undelivered_keys.iter().map(|key| key.as_slice()),
+                            );
+                            if !requeued.ids.is_empty() {
+                                let mut leases = leases.lock().unwrap();
+                                for id in &requeued.ids {
+                                    leases.remove(id);
+                                }
+                                queue_waker.notify_waiters();
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        ExpirySweeper {
+            stop: Mutex::new(Some(stop)),
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    fn stop(&self) {
+        self.stop.lock().unwrap().take();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ExpirySweeper {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn sweep_expired(
+    store: &Store,
+    keyspace: usize,
+    watchers: &WatchHub,
+    now: u64,
+) -> Result<(), heed::Error> {
+    let Store {
+        env, db, expiry, ..
+    } = store;
+    let end = (now + 1).to_be_bytes();
+    loop {
+        let mut txn = env.write_txn()?;
+        let mut expired = Vec::new();
+        for entry in expiry.iter(&txn)?.take(EXPIRY_SWEEP_BATCH_SIZE) {
+            let (index_key, _) = entry?;
+            if index_key >= &end[..] {
+                break;
+            }
+            expired.push(index_key.to_vec());
+        }
+        for index_key in &expired {
+            expiry.delete(&mut txn, index_key)?;
+            db.delete(&mut txn, &index_key[8..])?;
+        }
+        if !expired.is_empty() {
+            // Lets watchers in other processes notice the deletions.
+            store.next_version(&mut txn)?;
+        }
+        txn.commit()?;
+        watchers.notify(keyspace, expired.iter().map(|index_key| &index_key[8..]));
+
+        if expired.len() < EXPIRY_SWEEP_BATCH_SIZE {
+            return Ok(());
+        }
+    }
+}
+
+/// Creates the database in `txn`, or only opens it when there is no write
+/// transaction because the env is read-only.
+fn open_or_create<KC: 'static, DC: 'static>(
+    env: &heed::Env,
+    txn: Option<&mut RwTxn>,
+    name: &str,
+) -> Result<heed::Database<KC, DC>, LmdbError> {
+    match txn {
+        Some(txn) => Ok(env.create_database_with_txn(Some(name), txn)?),
+        None => env
+            .open_database(Some(name))?
+            .ok_or_else(|| LmdbError::MissingDatabase(name.to_owned())),
+    }
+}
+
+/// A versionstamp is the commit version followed by the write's index within
+/// its batch.
+fn versionstamp(version: u64, index: u16) -> Versionstamp {
+    let mut versionstamp = [0; 10];
+    versionstamp[..8].copy_from_slice(&version.to_be_bytes());
+    versionstamp[8..].copy_from_slice(&index.to_be_bytes());
+    versionstamp
+}
+
+fn expiry_key(expire_at: u64, key: &[u8]) -> Vec<u8> {
+    let mut index_key = expire_at.to_be_bytes().to_vec();
+    index_key.extend_from_slice(key);
+    index_key
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// The same operations as [`denokv_proto::Database`], but returning `Send`
+/// futures and streams, so they can be used from `tokio::spawn` on a
+/// multithreaded runtime.
+impl LmdbDatabase {
+    pub async fn snapshot_read(
+        &self,
+        requests: Vec<ReadRange>,
+        _options: SnapshotReadOptions,
+    ) -> Result<Vec<ReadRangeOutput>, LmdbError> {
+        limits::check_read(&requests)?;
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || db.read_ranges(&requests)).await?
+    }
+
+    /// Returns `None` if one of the write's checks failed.
+    pub async fn atomic_write(
+        &self,
+        write: AtomicWrite,
+    ) -> Result<Option<CommitResult>, LmdbError> {
+        self.atomic_write_to_queue(write, DEFAULT_QUEUE).await
+    }
+
+    /// Like [`LmdbDatabase::atomic_write`], but the write's messages are
+    /// enqueued into the named queue.
+    pub async fn atomic_write_to_queue(
+        &self,
+        write: AtomicWrite,
+        queue: &str,
+    ) -> Result<Option<CommitResult>, LmdbError> {
+        self.ensure_writable()?;
+        limits::check_write(&write)?;
+        limits::check_queue_name(queue)?;
+        match self.writer.commit(self.clone(), queue.into(), write).await {
+            Ok(result) => Ok(Some(result)),
+            Err(LmdbError::CheckFailed) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Waits for the next due message, or returns `None` once the database is
+    /// closed.
+    pub async fn dequeue_next_message(&self) -> Result<Option<LmdbMessageHandle>, LmdbError> {
+        self.dequeue_next_message_from(DEFAULT_QUEUE).await
+    }
+
+    /// Like [`LmdbDatabase::dequeue_next_message`], but takes messages from
+    /// the named queue only.
+    pub async fn dequeue_next_message_from(
+        &self,
+        queue: &str,
+    ) -> Result<Option<LmdbMessageHandle>, LmdbError> {
+        self.ensure_writable()?;
+        limits::check_queue_name(queue)?;
+        loop {
+            // Register for wakeups before looking at the queue, so that an
+            // enqueue committed in between is not missed.
+            let woken = self.queue_waker.notified();
+            let mut woken = pin!(woken);
+            woken.as_mut().enable();
+
+            if *self.closed.read().unwrap() {
+                return Ok(None);
+            }
+            match self.try_dequeue(queue.into()).await? {
+                Dequeue::Ready(handle) => return Ok(Some(handle)),
+                Dequeue::Pending(Some(deadline)) => {
+                    let due_in = Duration::from_millis(deadline.saturating_sub(now_millis()));
+                    let _ = tokio::time::timeout(due_in, woken).await;
+                }
+                Dequeue::Pending(None) => woken.await,
+            }
+        }
+    }
+
+    pub fn watch(
+        &self,
+        keys: Vec<Vec<u8>>,
+    ) -> BoxStream<'static, Result<Vec<WatchKeyOutput>, LmdbError>> {
+        let checked = limits::check_watch(&keys).and_then(|()| self.ensure_open().map(drop));
+        if let Err(e) = checked {
+            return Box::pin(stream::once(async { Err(e) }));
+        }
+        self.watch_keys(keys)
+    }
+
+    pub fn close(&self) {
+        {
+            let mut closed = self.closed.write().unwrap();
+            if *closed {
+                return;
+            }
+            *closed = true;
+        }
+
+        self.watchers.close(self.keyspace);
+        self.queue_waker.notify_waiters();
+        if self.open_handles.fetch_sub(1, Ordering::SeqCst) > 1 {
+            // Other keyspaces still use the env.
+            return;
+        }
+
+        self.sweeper.stop();
+        self.poller.stop();
+        self.writer.stop();
+        if self.config.is_read_only() {
+            return;
+        }
+        // Messages this process never finished go back to the queue
+        // unchanged, so the next consumer picks them up without using up a
+        // retry.
+        if let Ok(stores) = self.store() {
+            let leases = self.leases.lock().unwrap();
+            for store in stores.all() {
+                let _ = store.release_leases(&leases);
+            }
+            let _ = stores.env.force_sync();
+        }
     }
 }
 
@@ -107,63 +1057,27 @@ impl denokv_proto::Database for LmdbDatabase {
     async fn snapshot_read(
         &self,
         requests: Vec<ReadRange>,
-        _: SnapshotReadOptions,
+        options: SnapshotReadOptions,
     ) -> Result<Vec<ReadRangeOutput>, anyhow::Error> {
-        let mut res = Vec::<ReadRangeOutput>::new();
-        let txn = self.env.read_txn().map_err(|e| Error::msg(e.to_string()))?;
-        for req in requests {
-            let start_key = LmdbDKvKey(req.start);
-            let end_key = LmdbDKvKey(req.end);
-            let range = &(&start_key..&end_key);
-
-            let results: Box<dyn Iterator<Item = (LmdbDKvKey, LmdbDKvValue)>> = if req.reverse {
-                Box::new(
-                    (self
-                        .db
-                        .rev_range(&txn, range)
-                        .map_err(|e| Error::msg(e.to_string()))?)
-                    .flatten(),
-                )
-            } else {
-                Box::new(
-                    (self
-                        .db
-                        .range(&txn, range)
-                        .map_err(|e| Error::msg(e.to_string()))?)
-                    .flatten(),
-                )
-            };
-
-            res.push(ReadRangeOutput {
-                entries: results
-                    .map(|(k, v)| KvEntry {
-                        key: k.0,
-                        value: v.0,
-                        versionstamp: [0; 10],
-                    })
-                    .collect(),
-            });
-        }
-
-        Ok(res)
+        Ok(LmdbDatabase::snapshot_read(self, requests, options).await?)
     }
 
     async fn atomic_write(
         &self,
         write: AtomicWrite,
     ) -> Result<Option<CommitResult>, anyhow::Error> {
-        todo!()
+        Ok(LmdbDatabase::atomic_write(self, write).await?)
     }
 
     async fn dequeue_next_message(&self) -> Result<Option<Self::QMH>, anyhow::Error> {
-        todo!()
+        Ok(LmdbDatabase::dequeue_next_message(self).await?)
     }
 
     fn watch(&self, keys: Vec<Vec<u8>>) -> WatchStream {
-        todo!()
+        Box::pin(LmdbDatabase::watch(self, keys).map_err(anyhow::Error::from))
     }
 
     fn close(&self) {
-        todo!()
+        LmdbDatabase::close(self)
     }
 }
@@ -0,0 +1,177 @@
+use denokv_proto::{AtomicWrite, KvValue, MutationKind, ReadRange};
+
+use crate::{now_millis, LmdbError};
+
+// Mirrors `denokv_proto::limits`, which is not public.
+const MAX_WRITE_KEY_SIZE_BYTES: usize = 2048;
+const MAX_READ_KEY_SIZE_BYTES: usize = MAX_WRITE_KEY_SIZE_BYTES + 1;
+const MAX_VALUE_SIZE_BYTES: usize = 65536;
+const MAX_READ_RANGES: usize = 10;
+const MAX_READ_ENTRIES: usize = 1000;
+const MAX_CHECKS: usize = 10;
+const MAX_MUTATIONS: usize = 1000;
+const MAX_TOTAL_MUTATION_SIZE_BYTES: usize = 819200;
+const MAX_QUEUE_DELAY_MS: i64 = 30 * 24 * 60 * 60 * 1000;
+const MAX_QUEUE_UNDELIVERED_KEYS: usize = 10;
+const MAX_QUEUE_BACKOFF_INTERVALS: usize = 10;
+const MAX_QUEUE_BACKOFF_MS: u32 = 3600000;
+const MAX_WATCHED_KEYS: usize = 10;
+// Queue names are stored behind a one-byte length.
+const MAX_QUEUE_NAME_SIZE_BYTES: usize = u8::MAX as usize;
+
+fn exceeded(msg: impl Into<String>) -> LmdbError {
+    LmdbError::LimitExceeded(msg.into())
+}
+
+fn check_read_key(key: &[u8]) -> Result<(), LmdbError> {
+    if key.len() > MAX_READ_KEY_SIZE_BYTES {
+        return Err(exceeded(format!(
+            "Key too large for read (max {} bytes)",
+            MAX_READ_KEY_SIZE_BYTES
+        )));
+    }
+    Ok(())
+}
+
+fn check_write_key(key: &[u8]) -> Result<(), LmdbError> {
+    if key.len() > MAX_WRITE_KEY_SIZE_BYTES {
+        return Err(exceeded(format!(
+            "Key too large for write (max {} bytes)",
+            MAX_WRITE_KEY_SIZE_BYTES
+        )));
+    }
+    Ok(())
+}
+
+fn check_value_size(size: usize) -> Result<(), LmdbError> {
+    if size > MAX_VALUE_SIZE_BYTES {
+        return Err(exceeded(format!(
+            "Value too large (max {} bytes)",
+            MAX_VALUE_SIZE_BYTES
+        )));
+    }
+    Ok(())
+}
+
+fn value_size(value: &KvValue) -> usize {
+    match value {
+        KvValue::V8(bytes) | KvValue::Bytes(bytes) => bytes.len(),
+        KvValue::U64(_) => 8,
+    }
+}
+
+pub(crate) fn check_read(requests: &[ReadRange]) -> Result<(), LmdbError> {
+    if requests.len() > MAX_READ_RANGES {
+        return Err(exceeded(format!(
+            "Too many ranges (max {})",
+            MAX_READ_RANGES
+        )));
+    }
+    let mut total_limit = 0;
+    for request in requests {
+        check_read_key(&request.start)?;
+        check_read_key(&request.end)?;
+        total_limit += request.limit.get() as usize;
+    }
+    if total_limit > MAX_READ_ENTRIES {
+        return Err(exceeded(format!(
+            "Too many entries (max {})",
+            MAX_READ_ENTRIES
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_write(write: &AtomicWrite) -> Result<(), LmdbError> {
+    if write.checks.len() > MAX_CHECKS {
+        return Err(exceeded(format!("Too many checks (max {})", MAX_CHECKS)));
+    }
+    if write.mutations.len() + write.enqueues.len() > MAX_MUTATIONS {
+        return Err(exceeded(format!(
+            "Too many mutations (max {})",
+            MAX_MUTATIONS
+        )));
+    }
+
+    let mut total_size = 0;
+    for check in &write.checks {
+        check_read_key(&check.key)?;
+        total_size += check.key.len();
+    }
+    for mutation in &write.mutations {
+        check_write_key(&mutation.key)?;
+        let size = match &mutation.kind {
+            MutationKind::Set(value)
+            | MutationKind::Sum { value, .. }
+            | MutationKind::Min(value)
+            | MutationKind::Max(value)
+            | MutationKind::SetSuffixVersionstampedKey(value) => value_size(value),
+            MutationKind::Delete => 0,
+        };
+        check_value_size(size)?;
+        total_size += mutation.key.len() + size;
+    }
+
+    let now = now_millis() as i64;
+    for enqueue in &write.enqueues {
+        check_value_size(enqueue.payload.len())?;
+        total_size += enqueue.payload.len();
+        if enqueue.keys_if_undelivered.len() > MAX_QUEUE_UNDELIVERED_KEYS {
+            return Err(exceeded(format!(
+                "Too many undelivered keys (max {})",
+                MAX_QUEUE_UNDELIVERED_KEYS
+            )));
+        }
+        for key in &enqueue.keys_if_undelivered {
+            check_write_key(key)?;
+            total_size += key.len();
+        }
+        let backoff_schedule = enqueue.backoff_schedule.as_deref().unwrap_or_default();
+        if backoff_schedule.len() > MAX_QUEUE_BACKOFF_INTERVALS {
+            return Err(exceeded(format!(
+                "Too many backoff intervals (max {})",
+                MAX_QUEUE_BACKOFF_INTERVALS
+            )));
+        }
+        for interval in backoff_schedule {
+            if *interval > MAX_QUEUE_BACKOFF_MS {
+                return Err(exceeded(format!(
+                    "Backoff interval too large (max {} ms)",
+                    MAX_QUEUE_BACKOFF_MS
+                )));
+            }
+            total_size += 4;
+        }
+        if enqueue.deadline.timestamp_millis() - now > MAX_QUEUE_DELAY_MS {
+            return Err(exceeded("Delay cannot be greater than 30 days"));
+        }
+    }
+
+    if total_size > MAX_TOTAL_MUTATION_SIZE_BYTES {
+        return Err(exceeded(format!(
+            "Total mutation size too large (max {} bytes)",
+            MAX_TOTAL_MUTATION_SIZE_BYTES
+        )));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_watch(keys: &[Vec<u8>]) -> Result<(), LmdbError> {
+    if keys.len() > MAX_WATCHED_KEYS {
+        return Err(exceeded(format!(
+            "Too many keys to watch (max {})",
+            MAX_WATCHED_KEYS
+        )));
+    }
+    keys.iter().try_for_each(|key| check_read_key(key))
+}
+
+pub(crate) fn check_queue_name(queue: &str) -> Result<(), LmdbError> {
+    if queue.len() > MAX_QUEUE_NAME_SIZE_BYTES {
+        return Err(exceeded(format!(
+            "Queue name too large (max {} bytes)",
+            MAX_QUEUE_NAME_SIZE_BYTES
+        )));
+    }
+    Ok(())
+}
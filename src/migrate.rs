@@ -0,0 +1,526 @@
+use heed::{
+    types::{ByteSlice, OwnedType, SerdeBincode, Str, Unit},
+    Env, RwTxn,
+};
+
+use crate::{LmdbDKvKey, LmdbDKvValue, LmdbError, QueueMessage, VERSION_KEY};
+
+const FORMAT_VERSION_KEY: &str = "format_version";
+
+type Migration = fn(&Env, &mut RwTxn) -> Result<(), LmdbError>;
+
+/// `MIGRATIONS[n]` upgrades a database from format `n + 1` to `n + 2`.
+///
+/// 1. Values in the unnamed database, encoded as `[tag][payload]`.
+/// 2. Values in `kv`, encoded as `[tag][versionstamp][payload]`.
+/// 3. Values in `kv`, encoded as `[tag][versionstamp][expire_at][payload]`.
+/// 4. Queue messages carry a retry count.
+/// 5. Queue keys start with the name of the queue, and messages carry it.
+const MIGRATIONS: &[Migration] = &[
+    add_versionstamps,
+    add_expiry,
+    add_retry_counts,
+    add_queue_names,
+];
+
+pub(crate) const FORMAT_VERSION: u64 = MIGRATIONS.len() as u64 + 1;
+
+/// Bring the env up to [`FORMAT_VERSION`], refusing formats this crate does
+/// not understand.
+pub(crate) fn migrate(env: &Env, read_only: bool) -> Result<(), LmdbError> {
+    let recorded = recorded_format_version(env)?;
+    let version = match recorded {
+        Some(version) => version,
+        None => detect_format_version(env)?,
+    };
+    if version == 0 {
+        return Err(LmdbError::Corrupted("format version 0".into()));
+    }
+    if version > FORMAT_VERSION {
+        return Err(LmdbError::FormatTooNew {
+            found: version,
+            supported: FORMAT_VERSION,
+        });
+    }
+    if recorded == Some(FORMAT_VERSION) {
+        return Ok(());
+    }
+    if read_only {
+        if version < FORMAT_VERSION {
+            return Err(LmdbError::MigrationRequired(version));
+        }
+        return Ok(());
+    }
+
+    let mut txn = env.write_txn()?;
+    for migration in &MIGRATIONS[version as usize - 1..] {
+        migration(env, &mut txn)?;
+    }
+    let meta = env.create_database_with_txn::<Str, OwnedType<u64>>(Some("meta"), &mut txn)?;
+    meta.put(&mut txn, FORMAT_VERSION_KEY, &FORMAT_VERSION)?;
+    txn.commit()?;
+    Ok(())
+}
+
+fn recorded_format_version(env: &Env) -> Result<Option<u64>, LmdbError> {
+    let Some(meta) = env.open_database::<Str, OwnedType<u64>>(Some("meta"))? else {
+        return Ok(None);
+    };
+    let txn = env.read_txn()?;
+    Ok(meta.get(&txn, FORMAT_VERSION_KEY)?)
+}
+
+/// Databases written before the format version was recorded are recognised
+/// by the named databases they contain.
+fn detect_format_version(env: &Env) -> Result<u64, LmdbError> {
+    if env
+        .open_database::<ByteSlice, Unit>(Some("expiry"))?
+        .is_some()
+    {
+        return Ok(3);
+    }
+    if env
+        .open_database::<LmdbDKvKey, LmdbDKvValue>(Some("kv"))?
+        .is_some()
+    {
+        return Ok(2);
+    }
+    let Some(unnamed) = env.open_database::<ByteSlice, ByteSlice>(None)? else {
+        return Ok(FORMAT_VERSION);
+    };
+    let txn = env.read_txn()?;
+    if unnamed.is_empty(&txn)? {
+        Ok(FORMAT_VERSION)
+    } else {
+        Ok(1)
+    }
+}
+
+fn add_versionstamps(env: &Env, txn: &mut RwTxn) -> Result<(), LmdbError> {
+    let unnamed = env.create_database_with_txn::<ByteSlice, ByteSlice>(None, txn)?;
+    let entries = unnamed
+        .iter(txn)?
+        .map(|entry| entry.map(|(key, value)| (key.to_vec(), value.to_vec())))
+        .collect::<Result<Vec<_>, _>>()?;
+    // The unnamed database also holds the names of the named databases, so it
+    // has to be emptied before they are created.
+    unnamed.clear(txn)?;
+
+    let kv = env
+        .create_database_with_txn::<LmdbDKvKey, LmdbDKvValue>(Some("kv"), txn)?
+        .remap_types::<ByteSlice, ByteSlice>();
+    let meta = env.create_database_with_txn::<Str, OwnedType<u64>>(Some("meta"), txn)?;
+    if entries.is_empty() {
+        return Ok(());
+    }
+
+    // Every existing entry is treated as written by the first commit.
+    let version = 1u64;
+    meta.put(txn, VERSION_KEY, &version)?;
+    let mut versionstamp = [0; 10];
+    versionstamp[..8].copy_from_slice(&version.to_be_bytes());
+    for (key, value) in entries {
+        let Some((tag, payload)) = value.split_first() else {
+            return Err(LmdbError::Corrupted("empty format 1 value".into()));
+        };
+        let mut migrated = Vec::with_capacity(value.len() + versionstamp.len());
+        migrated.push(*tag);
+        migrated.extend_from_slice(&versionstamp);
+        migrated.extend_from_slice(payload);
+        kv.put(txn, &key, &migrated)?;
+    }
+    Ok(())
+}
+
+fn add_expiry(env: &Env, txn: &mut RwTxn) -> Result<(), LmdbError> {
+    let kv = env
+        .create_database_with_txn::<LmdbDKvKey, LmdbDKvValue>(Some("kv"), txn)?
+        .remap_types::<ByteSlice, ByteSlice>();
+    env.create_database_with_txn::<ByteSlice, Unit>(Some("expiry"), txn)?;
+
+    let entries = kv
+        .iter(txn)?
+        .map(|entry| entry.map(|(key, value)| (key.to_vec(), value.to_vec())))
+        .collect::<Result<Vec<_>, _>>()?;
+    for (key, value) in entries {
+        if value.len() < 11 {
+            return Err(LmdbError::Corrupted(format!(
+                "format 2 value is {} bytes, expected at least 11",
+                value.len()
+            )));
+        }
+        let (header, payload) = value.split_at(11);
+        let mut migrated = Vec::with_capacity(value.len() + 8);
+        migrated.extend_from_slice(header);
+        // No entry written before format 3 expires.
+        migrated.extend_from_slice(&0u64.to_be_bytes());
+        migrated.extend_from_slice(payload);
+        kv.put(txn, &key, &migrated)?;
+    }
+    Ok(())
+}
+
+fn add_retry_counts(env: &Env, txn: &mut RwTxn) -> Result<(), LmdbError> {
+    for name in ["queue", "queue_running"] {
+        let db = env
+            .create_database_with_txn::<ByteSlice, SerdeBincode<QueueMessage>>(Some(name), txn)?
+            .remap_types::<ByteSlice, ByteSlice>();
+        let entries = db
+            .iter(txn)?
+            .map(|entry| entry.map(|(key, value)| (key.to_vec(), value.to_vec())))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (key, mut value) in entries {
+            // bincode writes struct fields in order, so the new trailing
+            // `retries` field is a little-endian u32 at the end.
+            value.extend_from_slice(&0u32.to_le_bytes());
+            db.put(txn, &key, &value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Existing messages all belong to the default queue, whose name is empty.
+fn add_queue_names(env: &Env, txn: &mut RwTxn) -> Result<(), LmdbError> {
+    for name in ["queue", "queue_running"] {
+        let db = env
+            .create_database_with_txn::<ByteSlice, SerdeBincode<QueueMessage>>(Some(name), txn)?
+            .remap_types::<ByteSlice, ByteSlice>();
+        let entries = db
+            .iter(txn)?
+            .map(|entry| entry.map(|(key, value)| (key.to_vec(), value.to_vec())))
+            .collect::<Result<Vec<_>, _>>()?;
+        db.clear(txn)?;
+        for (mut key, mut value) in entries {
+            // Running messages are keyed by id alone; queued ones get the
+            // length of the empty name as a prefix.
+            if name == "queue" {
+                key.insert(0, 0);
+            }
+            // The new trailing `queue` field is an empty string, which
+            // bincode writes as a zero u64 length.
+            value.extend_from_slice(&0u64.to_le_bytes());
+            db.put(txn, &key, &value)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use heed::{types::SerdeBincode, BytesEncode, EnvOpenOptions};
+    use serde::Serialize;
+
+    use super::*;
+    use crate::{
+        now_millis, test_util, KvValueRef, LmdbDatabase, QueueMessageState, DEFAULT_QUEUE,
+    };
+
+    /// A stored value, comparable in assertions.
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        V8(Vec<u8>),
+        Bytes(Vec<u8>),
+        U64(u64),
+    }
+
+    /// A queue message as formats 3 and 4 stored it. Format 3 had no
+    /// `retries`, which is left off by [`encode_message`].
+    #[derive(Serialize)]
+    struct OldMessage {
+        payload: Vec<u8>,
+        deadline: u64,
+        keys_if_undelivered: Vec<Vec<u8>>,
+        backoff_schedule: Vec<u32>,
+        retries: u32,
+    }
+
+    fn encode_message(message: &OldMessage, with_retries: bool) -> Vec<u8> {
+        let mut bytes = SerdeBincode::<OldMessage>::bytes_encode(message)
+            .unwrap()
+            .into_owned();
+        if !with_retries {
+            bytes.truncate(bytes.len() - 4);
+        }
+        bytes
+    }
+
+    fn write_fixture(path: &Path, write: impl FnOnce(&Env, &mut RwTxn)) {
+        let env = EnvOpenOptions::new()
+            .max_dbs(8)
+            .map_size(1 << 20)
+            .open(path)
+            .unwrap();
+        let mut txn = env.write_txn().unwrap();
+        write(&env, &mut txn);
+        txn.commit().unwrap();
+        env.prepare_for_closing().wait();
+    }
+
+    fn raw_db(
+        env: &Env,
+        txn: &mut RwTxn,
+        name: Option<&str>,
+    ) -> heed::Database<ByteSlice, ByteSlice> {
+        env.create_database_with_txn(name, txn).unwrap()
+    }
+
+    fn set_meta(env: &Env, txn: &mut RwTxn, key: &str, value: u64) {
+        env.create_database_with_txn::<Str, OwnedType<u64>>(Some("meta"), txn)
+            .unwrap()
+            .put(txn, key, &value)
+            .unwrap();
+    }
+
+    fn versionstamp(version: u64) -> [u8; 10] {
+        crate::versionstamp(version, 0)
+    }
+
+    /// The value, versionstamp and expiry stored for `key`, if it is live.
+    fn entry(db: &LmdbDatabase, key: &[u8]) -> Option<(Value, [u8; 10], Option<u64>)> {
+        let store = db.store().unwrap();
+        let txn = store.env.read_txn().unwrap();
+        store
+            .get_live(&txn, key, now_millis())
+            .unwrap()
+            .map(|record| {
+                let value = match record.value {
+                    KvValueRef::V8(bytes) => Value::V8(bytes.to_vec()),
+                    KvValueRef::Bytes(bytes) => Value::Bytes(bytes.to_vec()),
+                    KvValueRef::U64(n) => Value::U64(n),
+                };
+                (value, record.versionstamp, record.expire_at)
+            })
+    }
+
+    fn format_version(db: &LmdbDatabase) -> Option<u64> {
+        recorded_format_version(&db.store().unwrap().env).unwrap()
+    }
+
+    async fn set(db: &LmdbDatabase, key: &[u8]) -> [u8; 10] {
+        let write = test_util::write(vec![], vec![test_util::set(key, b"new")]);
+        db.atomic_write(write).await.unwrap().unwrap().versionstamp
+    }
+
+    #[tokio::test]
+    async fn migrates_format_1() {
+        let dir = tempfile::tempdir().unwrap();
+        write_fixture(dir.path(), |env, txn| {
+            let unnamed = raw_db(env, txn, None);
+            unnamed.put(txn, b"bytes", &[1, b'a', b'b']).unwrap();
+            unnamed
+                .put(txn, b"u64", &[&[0][..], &7u64.to_le_bytes()].concat())
+                .unwrap();
+            unnamed.put(txn, b"v8", &[2, 0xff, 0x0f]).unwrap();
+        });
+
+        let db = LmdbDatabase::new(dir.path()).unwrap();
+        assert_eq!(format_version(&db), Some(FORMAT_VERSION));
+        let first = versionstamp(1);
+        assert_eq!(
+            entry(&db, b"bytes"),
+            Some((Value::Bytes(b"ab".to_vec()), first, None))
+        );
+        assert_eq!(entry(&db, b"u64"), Some((Value::U64(7), first, None)));
+        assert_eq!(
+            entry(&db, b"v8"),
+            Some((Value::V8(vec![0xff, 0x0f]), first, None))
+        );
+        assert_eq!(set(&db, b"bytes").await, versionstamp(2));
+        db.close();
+    }
+
+    #[tokio::test]
+    async fn migrates_format_2() {
+        let dir = tempfile::tempdir().unwrap();
+        write_fixture(dir.path(), |env, txn| {
+            let kv = raw_db(env, txn, Some("kv"));
+            let value = [&[1][..], &versionstamp(4), b"four"].concat();
+            kv.put(txn, b"a", &value).unwrap();
+            let value = [&[0][..], &versionstamp(9), &3u64.to_le_bytes()].concat();
+            kv.put(txn, b"n", &value).unwrap();
+            set_meta(env, txn, VERSION_KEY, 9);
+        });
+
+        let db = LmdbDatabase::new(dir.path()).unwrap();
+        assert_eq!(format_version(&db), Some(FORMAT_VERSION));
+        assert_eq!(
+            entry(&db, b"a"),
+            Some((Value::Bytes(b"four".to_vec()), versionstamp(4), None))
+        );
+        assert_eq!(
+            entry(&db, b"n"),
+            Some((Value::U64(3), versionstamp(9), None))
+        );
+        assert_eq!(set(&db, b"a").await, versionstamp(10));
+        db.close();
+    }
+
+    /// Format 3 databases written before the format version was recorded are
+    /// recognised by their `expiry` database.
+    #[tokio::test]
+    async fn migrates_format_3() {
+        for recorded in [false, true] {
+            let dir = tempfile::tempdir().unwrap();
+            let later = now_millis() + 3_600_000;
+            write_fixture(dir.path(), |env, txn| {
+                let kv = raw_db(env, txn, Some("kv"));
+                let expiry = raw_db(env, txn, Some("expiry"));
+                for (key, expire_at) in [(&b"live"[..], later), (b"expired", 1)] {
+                    let value = [&[1][..], &versionstamp(2), &expire_at.to_be_bytes(), b"v"];
+                    kv.put(txn, key, &value.concat()).unwrap();
+                    expiry
+                        .put(txn, &[&expire_at.to_be_bytes()[..], key].concat(), &[])
+                        .unwrap();
+                }
+                let message = OldMessage {
+                    payload: b"job".to_vec(),
+                    deadline: 5,
+                    keys_if_undelivered: vec![b"dead".to_vec()],
+                    backoff_schedule: vec![100],
+                    retries: 0,
+                };
+                let key = [&5u64.to_be_bytes()[..], &[7; 12]].concat();
+                raw_db(env, txn, Some("queue"))
+                    .put(txn, &key, &encode_message(&message, false))
+                    .unwrap();
+                raw_db(env, txn, Some("queue_running"));
+                set_meta(env, txn, VERSION_KEY, 2);
+                if recorded {
+                    set_meta(env, txn, FORMAT_VERSION_KEY, 3);
+                }
+            });
+
+            let db = LmdbDatabase::new(dir.path()).unwrap();
+            assert_eq!(format_version(&db), Some(FORMAT_VERSION));
+            assert_eq!(
+                entry(&db, b"live"),
+                Some((Value::Bytes(b"v".to_vec()), versionstamp(2), Some(later)))
+            );
+            assert_eq!(entry(&db, b"expired"), None);
+
+            let messages = db.queue_messages().await.unwrap();
+            assert_eq!(messages.len(), 1);
+            let message = &messages[0];
+            assert_eq!(message.id, [7; 12]);
+            assert_eq!(message.queue, DEFAULT_QUEUE);
+            assert_eq!(message.state, QueueMessageState::Pending);
+            assert_eq!(message.deadline.timestamp_millis(), 5);
+            assert_eq!(message.retries, 0);
+            assert_eq!(message.payload, b"job");
+            assert_eq!(message.keys_if_undelivered, [b"dead".to_vec()]);
+            assert_eq!(message.backoff_schedule, [100]);
+
+            let mut handle = db.dequeue_next_message().await.unwrap().unwrap();
+            assert_eq!(handle.take_payload().await.unwrap(), b"job");
+            handle.finish(true).await.unwrap();
+            db.close();
+        }
+    }
+
+    #[tokio::test]
+    async fn migrates_format_4() {
+        let dir = tempfile::tempdir().unwrap();
+        write_fixture(dir.path(), |env, txn| {
+            let kv = raw_db(env, txn, Some("kv"));
+            let value = [&[1][..], &versionstamp(3), &0u64.to_be_bytes(), b"v"];
+            kv.put(txn, b"k", &value.concat()).unwrap();
+            raw_db(env, txn, Some("expiry"));
+
+            let queued = OldMessage {
+                payload: b"queued".to_vec(),
+                deadline: 5,
+                keys_if_undelivered: vec![],
+                backoff_schedule: vec![],
+                retries: 2,
+            };
+            let key = [&5u64.to_be_bytes()[..], &[1; 12]].concat();
+            raw_db(env, txn, Some("queue"))
+                .put(txn, &key, &encode_message(&queued, true))
+                .unwrap();
+            // Still leased, maybe to another process sharing the env.
+            let running = OldMessage {
+                payload: b"running".to_vec(),
+                deadline: u64::MAX,
+                keys_if_undelivered: vec![],
+                backoff_schedule: vec![0, 1000],
+                retries: 1,
+            };
+            raw_db(env, txn, Some("queue_running"))
+                .put(txn, &[2; 12], &encode_message(&running, true))
+                .unwrap();
+            set_meta(env, txn, VERSION_KEY, 3);
+            set_meta(env, txn, FORMAT_VERSION_KEY, 4);
+        });
+
+        let db = LmdbDatabase::new(dir.path()).unwrap();
+        assert_eq!(format_version(&db), Some(FORMAT_VERSION));
+        assert_eq!(
+            entry(&db, b"k"),
+            Some((Value::Bytes(b"v".to_vec()), versionstamp(3), None))
+        );
+
+        let messages = db.queue_messages().await.unwrap();
+        let summary = messages
+            .iter()
+            .map(|m| {
+                (
+                    m.id,
+                    m.queue.as_str(),
+                    m.state,
+                    m.retries,
+                    m.backoff_schedule.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                // The lease is left for the sweeper to expire.
+                (
+                    [2; 12],
+                    DEFAULT_QUEUE,
+                    QueueMessageState::InFlight,
+                    1,
+                    vec![0, 1000]
+                ),
+                (
+                    [1; 12],
+                    DEFAULT_QUEUE,
+                    QueueMessageState::Pending,
+                    2,
+                    vec![]
+                ),
+            ]
+        );
+        db.close();
+    }
+
+    #[tokio::test]
+    async fn read_only_open_requires_migration() {
+        let dir = tempfile::tempdir().unwrap();
+        write_fixture(dir.path(), |env, txn| {
+            raw_db(env, txn, Some("kv"));
+            raw_db(env, txn, Some("expiry"));
+            set_meta(env, txn, FORMAT_VERSION_KEY, 4);
+        });
+        assert!(matches!(
+            LmdbDatabase::open_read_only(dir.path()),
+            Err(LmdbError::MigrationRequired(4))
+        ));
+    }
+
+    #[test]
+    fn refuses_newer_format() {
+        let dir = tempfile::tempdir().unwrap();
+        write_fixture(dir.path(), |env, txn| {
+            set_meta(env, txn, FORMAT_VERSION_KEY, FORMAT_VERSION + 1);
+        });
+        assert!(matches!(
+            LmdbDatabase::new(dir.path()),
+            Err(LmdbError::FormatTooNew { found, supported })
+                if found == FORMAT_VERSION + 1 && supported == FORMAT_VERSION
+        ));
+    }
+}
@@ -0,0 +1,595 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use denokv_proto::{Enqueue, QueueMessageHandle, Versionstamp};
+use heed::{types::DecodeIgnore, RoTxn, RwTxn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    limits, now_millis, versionstamp, KvRecord, KvValueRef, LmdbDatabase, LmdbError, Store,
+};
+
+const DEFAULT_BACKOFF_SCHEDULE: [u32; 5] = [100, 1000, 5000, 30000, 60000];
+
+/// The queue used by [`denokv_proto::Database`].
+pub const DEFAULT_QUEUE: &str = "";
+
+/// The versionstamp of the write that enqueued a message, followed by the
+/// message's index within that write.
+pub type QueueMessageId = [u8; 12];
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct QueueMessage {
+    payload: Vec<u8>,
+    /// When the message is due, or for a running message, when its lease
+    /// expires.
+    deadline: u64,
+    keys_if_undelivered: Vec<Vec<u8>>,
+    backoff_schedule: Vec<u32>,
+    /// How many times a delivery did not succeed and the message was put back
+    /// in the queue.
+    retries: u32,
+    queue: String,
+}
+
+/// What `Store::expire_leases` did with the messages whose lease expired.
+#[derive(Default)]
+pub(crate) struct Requeued {
+    pub(crate) ids: Vec<QueueMessageId>,
+    pub(crate) undelivered_keys: Vec<Vec<u8>>,
+}
+
+/// Leases taken by this process, by message id, with the expiry each was
+/// granted. Other processes sharing the env hold leases of their own, which
+/// are left for the sweeper to expire.
+pub(crate) type Leases = Mutex<HashMap<QueueMessageId, u64>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueMessageState {
+    /// Due and waiting for a consumer.
+    Pending,
+    /// Waiting for its deadline.
+    Scheduled,
+    /// Dequeued and leased to a consumer.
+    InFlight,
+}
+
+#[derive(Clone, Debug)]
+pub struct QueueMessageInfo {
+    pub id: QueueMessageId,
+    pub queue: String,
+    pub state: QueueMessageState,
+    /// When the message is due, or for an in-flight message, when its lease
+    /// expires.
+    pub deadline: DateTime<Utc>,
+    /// How many times a delivery did not succeed and the message was put back
+    /// in the queue.
+    pub retries: u32,
+    pub payload: Vec<u8>,
+    pub keys_if_undelivered: Vec<Vec<u8>>,
+    /// Backoff intervals left for future retries, in milliseconds.
+    pub backoff_schedule: Vec<u32>,
+}
+
+impl QueueMessage {
+    fn into_info(self, id: QueueMessageId, state: QueueMessageState) -> QueueMessageInfo {
+        QueueMessageInfo {
+            id,
+            queue: self.queue,
+            state,
+            deadline: DateTime::from_timestamp_millis(self.deadline as i64)
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+            retries: self.retries,
+            payload: self.payload,
+            keys_if_undelivered: self.keys_if_undelivered,
+            backoff_schedule: self.backoff_schedule,
+        }
+    }
+}
+
+pub(crate) enum Dequeue {
+    Ready(LmdbMessageHandle),
+    /// Nothing is due yet; holds the deadline of the earliest message, if any.
+    Pending(Option<u64>),
+}
+
+pub struct LmdbMessageHandle {
+    db: LmdbDatabase,
+    id: QueueMessageId,
+    payload: Option<Vec<u8>>,
+}
+
+impl LmdbMessageHandle {
+    pub fn id(&self) -> QueueMessageId {
+        self.id
+    }
+
+    pub async fn take_payload(&mut self) -> Result<Vec<u8>, LmdbError> {
+        self.payload.take().ok_or(LmdbError::PayloadTaken)
+    }
+
+    pub async fn finish(&self, success: bool) -> Result<(), LmdbError> {
+        self.db.finish_message(self.id, success).await
+    }
+}
+
+#[async_trait(?Send)]
+impl QueueMessageHandle for LmdbMessageHandle {
+    async fn take_payload(&mut self) -> Result<Vec<u8>, anyhow::Error> {
+        Ok(LmdbMessageHandle::take_payload(self).await?)
+    }
+
+    async fn finish(&self, success: bool) -> Result<(), anyhow::Error> {
+        Ok(LmdbMessageHandle::finish(self, success).await?)
+    }
+}
+
+impl LmdbDatabase {
+    /// Lists every message in the queue: in-flight messages first, then the
+    /// others in deadline order.
+    pub async fn queue_messages(&self) -> Result<Vec<QueueMessageInfo>, LmdbError> {
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let _open = db.ensure_open()?;
+            db.with_store(|store| Ok(store.queue_messages(now_millis())?))
+        })
+        .await?
+    }
+
+    /// Deletes every message in the named queue, including in-flight ones,
+    /// whose handles then finish without effect. Returns how many messages
+    /// were deleted.
+    pub async fn purge_queue(&self, queue: &str) -> Result<usize, LmdbError> {
+        limits::check_queue_name(queue)?;
+        let queue = queue.to_owned();
+        self.write_queue(move |store| Ok(store.purge_queue(&queue)?))
+            .await
+    }
+
+    /// Makes a scheduled or in-flight message due immediately. Returns `false`
+    /// if there is no such message.
+    pub async fn requeue_message(&self, id: QueueMessageId) -> Result<bool, LmdbError> {
+        self.leases.lock().unwrap().remove(&id);
+        let requeued = self
+            .write_queue(move |store| Ok(store.requeue_message(&id)?))
+            .await?;
+        if requeued {
+            self.queue_waker.notify_waiters();
+        }
+        Ok(requeued)
+    }
+
+    /// Returns `false` if there is no such message.
+    pub async fn delete_message(&self, id: QueueMessageId) -> Result<bool, LmdbError> {
+        self.leases.lock().unwrap().remove(&id);
+        self.write_queue(move |store| Ok(store.delete_message(&id)?))
+            .await
+    }
+
+    pub(crate) async fn try_dequeue(&self, queue: String) -> Result<Dequeue, LmdbError> {
+        // Consumers woken by every commit look at the queue head in a read
+        // transaction first, and only take the writer when a message is due.
+        let db = self.clone();
+        let peeked = queue.clone();
+        let next_deadline = tokio::task::spawn_blocking(move || {
+            let _open = db.ensure_open()?;
+            db.with_store(|store| Ok(store.next_deadline(&peeked)?))
+        })
+        .await??;
+        match next_deadline {
+            None => return Ok(Dequeue::Pending(None)),
+            Some(deadline) if deadline > now_millis() => {
+                return Ok(Dequeue::Pending(Some(deadline)))
+            }
+            Some(_) => {}
+        }
+
+        let lease = self.config.message_lease_millis();
+        let leases = self.leases.clone();
+        let next = self
+            .write_queue(move |store| {
+                let next = store.take_next_message(&queue, lease)?;
+                if let Ok((id, message)) = &next {
+                    leases.lock().unwrap().insert(*id, message.deadline);
+                }
+                Ok(next)
+            })
+            .await?;
+        let (id, message) = match next {
+            Ok(next) => next,
+            Err(deadline) => return Ok(Dequeue::Pending(deadline)),
+        };
+
+        Ok(Dequeue::Ready(LmdbMessageHandle {
+            db: self.clone(),
+            id,
+            payload: Some(message.payload),
+        }))
+    }
+
+    async fn finish_message(&self, id: QueueMessageId, success: bool) -> Result<(), LmdbError> {
+        self.leases.lock().unwrap().remove(&id);
+        let undelivered_keys = self
+            .write_queue(move |store| store.finish_message(&id, success))
+            .await?;
+        self.watchers.notify(
+            self.keyspace,
+            undelivered_keys.iter().map(|key| key.as_slice()),
+        );
+        if !success {
+            // The message may have been rescheduled before the deadline a
+            // consumer is currently waiting for.
+            self.queue_waker.notify_waiters();
+        }
+        Ok(())
+    }
+
+    /// Run a queue write on the writer thread.
+    async fn write_queue<T: Send + 'static>(
+        &self,
+        mut write: impl FnMut(&Store) -> Result<T, LmdbError> + Send + 'static,
+    ) -> Result<T, LmdbError> {
+        self.ensure_writable()?;
+        let db = self.clone();
+        self.writer
+            .run(move || {
+                let _open = db.ensure_open()?;
+                db.with_map_growth(|store| write(store))
+            })
+            .await
+    }
+}
+
+impl Store {
+    pub(crate) fn enqueue(
+        &self,
+        txn: &mut RwTxn,
+        queue: &str,
+        versionstamp: Versionstamp,
+        enqueues: &[Enqueue],
+    ) -> Result<(), heed::Error> {
+        for (i, enqueue) in enqueues.iter().enumerate() {
+            let mut id = [0; 12];
+            id[..10].copy_from_slice(&versionstamp);
+            id[10..].copy_from_slice(&(i as u16).to_be_bytes());
+
+            let message = QueueMessage {
+                payload: enqueue.payload.clone(),
+                deadline: enqueue.deadline.timestamp_millis().max(0) as u64,
+                keys_if_undelivered: enqueue.keys_if_undelivered.clone(),
+                backoff_schedule: enqueue
+                    .backoff_schedule
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_BACKOFF_SCHEDULE.to_vec()),
+                retries: 0,
+                queue: queue.to_owned(),
+            };
+            self.queue
+                .put(txn, &queue_key(queue, message.deadline, &id), &message)?;
+        }
+        Ok(())
+    }
+
+    /// The deadline of the earliest message in `queue`, read from its key.
+    fn next_deadline(&self, queue: &str) -> Result<Option<u64>, heed::Error> {
+        let txn = self.env.read_txn()?;
+        let prefix = queue_prefix(queue);
+        let first = self
+            .queue
+            .remap_data_type::<DecodeIgnore>()
+            .prefix_iter(&txn, &prefix)?
+            .next()
+            .transpose()?;
+        first
+            .map(|(key, ())| {
+                key.get(prefix.len()..prefix.len() + 8)
+                    .and_then(|deadline| deadline.try_into().ok())
+                    .map(u64::from_be_bytes)
+                    .ok_or_else(|| {
+                        heed::Error::Decoding(Box::new(LmdbError::Corrupted(format!(
+                            "queue key is {} bytes, too short for a deadline",
+                            key.len()
+                        ))))
+                    })
+            })
+            .transpose()
+    }
+
+    /// Moves the earliest due message of `queue` to the running set, leased
+    /// for `lease` milliseconds. If none is due, returns the deadline of the
+    /// earliest message instead.
+    fn take_next_message(
+        &self,
+        queue: &str,
+        lease: u64,
+    ) -> Result<Result<(QueueMessageId, QueueMessage), Option<u64>>, heed::Error> {
+        let mut txn = self.env.write_txn()?;
+        let first = self.queue.prefix_iter(&txn, &queue_prefix(queue))?.next();
+        let Some((key, mut message)) = first.transpose()? else {
+            return Ok(Err(None));
+        };
+        let now = now_millis();
+        if message.deadline > now {
+            return Ok(Err(Some(message.deadline)));
+        }
+
+        let key = key.to_vec();
+        let id = queue_message_id(&key)?;
+        self.queue.delete(&mut txn, &key)?;
+        // In the running set, the deadline is when the lease expires.
+        message.deadline = now + lease;
+        self.queue_running.put(&mut txn, &id, &message)?;
+        txn.commit()?;
+
+        Ok(Ok((id, message)))
+    }
+
+    /// Treats running messages whose lease expired at or before `now` as
+    /// failed deliveries: each is retried after its next backoff, or written
+    /// to its undelivered keys once the schedule is used up.
+    pub(crate) fn expire_leases(&self, now: u64) -> Result<Requeued, heed::Error> {
+        let mut txn = self.env.write_txn()?;
+        let expired = self
+            .queue_running
+            .iter(&txn)?
+            .filter(|entry| !matches!(entry, Ok((_, message)) if message.deadline > now))
+            .map(|entry| entry.map(|(id, message)| (id.to_vec(), message)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if expired.is_empty() {
+            return Ok(Requeued::default());
+        }
+        let mut requeued = Requeued::default();
+        for (id, message) in expired {
+            self.queue_running.delete(&mut txn, &id)?;
+            let keys = self.retry_or_give_up(&mut txn, &id, message)?;
+            requeued.ids.push(queue_message_id(&id)?);
+            requeued.undelivered_keys.extend(keys);
+        }
+        txn.commit()?;
+        Ok(requeued)
+    }
+
+    /// Puts the running messages leased by this process back in the queue as
+    /// they were, due immediately. Returns how many were moved.
+    pub(crate) fn release_leases(
+        &self,
+        leases: &HashMap<QueueMessageId, u64>,
+    ) -> Result<usize, heed::Error> {
+        let mut txn = self.env.write_txn()?;
+        let now = now_millis();
+        let mut released = 0;
+        for (id, expiry) in leases {
+            // A lease that expired may since have been granted to someone
+            // else, with a different expiry.
+            let Some(mut message) = self
+                .queue_running
+                .get(&txn, id)?
+                .filter(|message| message.deadline == *expiry)
+            else {
+                continue;
+            };
+            self.queue_running.delete(&mut txn, id)?;
+            message.deadline = message.deadline.min(now);
+            self.queue.put(
+                &mut txn,
+                &queue_key(&message.queue, message.deadline, id),
+                &message,
+            )?;
+            released += 1;
+        }
+        if released > 0 {
+            txn.commit()?;
+        }
+        Ok(released)
+    }
+
+    /// Returns the keys that received the payload of an undeliverable message.
+    fn finish_message(
+        &self,
+        id: &QueueMessageId,
+        success: bool,
+    ) -> Result<Vec<Vec<u8>>, LmdbError> {
+        let mut txn = self.env.write_txn()?;
+        let Some(message) = self.queue_running.get(&txn, id)? else {
+            return Ok(Vec::new());
+        };
+
+        self.queue_running.delete(&mut txn, id)?;
+        let mut undelivered_keys = Vec::new();
+        if !success {
+            undelivered_keys = self.retry_or_give_up(&mut txn, id, message)?;
+        }
+
+        txn.commit()?;
+        Ok(undelivered_keys)
+    }
+
+    /// Puts a message whose delivery failed back in the queue after its next
+    /// backoff. Once the schedule is used up, its payload is written to its
+    /// undelivered keys instead, and those keys are returned.
+    fn retry_or_give_up(
+        &self,
+        txn: &mut RwTxn,
+        id: &[u8],
+        mut message: QueueMessage,
+    ) -> Result<Vec<Vec<u8>>, heed::Error> {
+        if message.backoff_schedule.is_empty() {
+            self.write_undelivered(txn, &message)?;
+            return Ok(message.keys_if_undelivered);
+        }
+
+        let backoff = message.backoff_schedule.remove(0);
+        message.deadline = now_millis() + backoff as u64;
+        message.retries += 1;
+        self.queue.put(
+            txn,
+            &queue_key(&message.queue, message.deadline, id),
+            &message,
+        )?;
+        Ok(Vec::new())
+    }
+
+    fn write_undelivered(
+        &self,
+        txn: &mut RwTxn,
+        message: &QueueMessage,
+    ) -> Result<(), heed::Error> {
+        if message.keys_if_undelivered.is_empty() {
+            return Ok(());
+        }
+
+        let versionstamp = versionstamp(self.next_version(txn)?, 0);
+        for key in &message.keys_if_undelivered {
+            self.put_entry(
+                txn,
+                key,
+                &KvRecord {
+                    value: KvValueRef::V8(&message.payload),
+                    versionstamp,
+                    expire_at: None,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    fn queue_messages(&self, now: u64) -> Result<Vec<QueueMessageInfo>, heed::Error> {
+        let txn = self.env.read_txn()?;
+        let mut messages = Vec::new();
+        for entry in self.queue_running.iter(&txn)? {
+            let (id, message) = entry?;
+            messages.push(message.into_info(queue_message_id(id)?, QueueMessageState::InFlight));
+        }
+        let mut queued = Vec::new();
+        for entry in self.queue.iter(&txn)? {
+            let (key, message) = entry?;
+            queued.push((queue_message_id(key)?, message));
+        }
+        // Keys are grouped by queue name before deadline.
+        queued.sort_by_key(|(_, message)| message.deadline);
+        for (id, message) in queued {
+            let state = if message.deadline <= now {
+                QueueMessageState::Pending
+            } else {
+                QueueMessageState::Scheduled
+            };
+            messages.push(message.into_info(id, state));
+        }
+        Ok(messages)
+    }
+
+    fn purge_queue(&self, queue: &str) -> Result<usize, heed::Error> {
+        let mut txn = self.env.write_txn()?;
+        let queued = self
+            .queue
+            .remap_data_type::<DecodeIgnore>()
+            .prefix_iter(&txn, &queue_prefix(queue))?
+            .map(|entry| entry.map(|(key, ())| key.to_vec()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let running = self
+            .queue_running
+            .iter(&txn)?
+            .filter(|entry| !matches!(entry, Ok((_, message)) if message.queue != queue))
+            .map(|entry| entry.map(|(id, _)| id.to_vec()))
+            .collect::<Result<Vec<_>, _>>()?;
+        for key in &queued {
+            self.queue.delete(&mut txn, key)?;
+        }
+        for id in &running {
+            self.queue_running.delete(&mut txn, id)?;
+        }
+        txn.commit()?;
+        Ok(queued.len() + running.len())
+    }
+
+    fn requeue_message(&self, id: &QueueMessageId) -> Result<bool, heed::Error> {
+        let mut txn = self.env.write_txn()?;
+        let Some(mut message) = self.remove_message(&mut txn, id)? else {
+            return Ok(false);
+        };
+        message.deadline = message.deadline.min(now_millis());
+        self.queue.put(
+            &mut txn,
+            &queue_key(&message.queue, message.deadline, id),
+            &message,
+        )?;
+        txn.commit()?;
+        Ok(true)
+    }
+
+    fn delete_message(&self, id: &QueueMessageId) -> Result<bool, heed::Error> {
+        let mut txn = self.env.write_txn()?;
+        if self.remove_message(&mut txn, id)?.is_none() {
+            return Ok(false);
+        }
+        txn.commit()?;
+        Ok(true)
+    }
+
+    /// Removes a message from either the running set or the queue.
+    fn remove_message(
+        &self,
+        txn: &mut RwTxn,
+        id: &QueueMessageId,
+    ) -> Result<Option<QueueMessage>, heed::Error> {
+        if let Some(message) = self.queue_running.get(txn, id)? {
+            self.queue_running.delete(txn, id)?;
+            return Ok(Some(message));
+        }
+        let Some(key) = self.find_queued(txn, id)? else {
+            return Ok(None);
+        };
+        let message = self.queue.get(txn, &key)?;
+        self.queue.delete(txn, &key)?;
+        Ok(message)
+    }
+
+    /// Queue keys are ordered by queue name and deadline and end with the id,
+    /// so finding a message by id alone takes a scan.
+    fn find_queued(
+        &self,
+        txn: &RoTxn,
+        id: &QueueMessageId,
+    ) -> Result<Option<Vec<u8>>, heed::Error> {
+        for entry in self
+            .queue
+            .remap_data_type::<heed::types::DecodeIgnore>()
+            .iter(txn)?
+        {
+            let (key, ()) = entry?;
+            if key.ends_with(id) {
+                return Ok(Some(key.to_vec()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Queue keys are `[name length][name][deadline][id]`, so each queue is a
+/// contiguous range ordered by deadline.
+fn queue_key(queue: &str, deadline: u64, id: &[u8]) -> Vec<u8> {
+    let mut key = queue_prefix(queue);
+    key.extend_from_slice(&deadline.to_be_bytes());
+    key.extend_from_slice(id);
+    key
+}
+
+fn queue_prefix(queue: &str) -> Vec<u8> {
+    // Names are checked against the limit before they get here, so the
+    // length fits in the one byte the key has for it.
+    debug_assert!(queue.len() <= u8::MAX as usize, "queue name too long");
+    let mut prefix = Vec::with_capacity(1 + queue.len() + 8 + 12);
+    prefix.push(queue.len() as u8);
+    prefix.extend_from_slice(queue.as_bytes());
+    prefix
+}
+
+/// The id of a message, from its key in either the queue or the running set.
+fn queue_message_id(key: &[u8]) -> Result<QueueMessageId, heed::Error> {
+    key[key.len().saturating_sub(12)..].try_into().map_err(|_| {
+        heed::Error::Decoding(Box::new(LmdbError::Corrupted(
+            "queue key is too short".into(),
+        )))
+    })
+}
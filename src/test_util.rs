@@ -0,0 +1,20 @@
+//! Builders shared by the unit tests.
+
+use denokv_proto::{AtomicWrite, Check, KvValue, Mutation, MutationKind};
+
+/// Sets `key` to a bytes value.
+pub(crate) fn set(key: &[u8], value: &[u8]) -> Mutation {
+    Mutation {
+        key: key.to_vec(),
+        kind: MutationKind::Set(KvValue::Bytes(value.to_vec())),
+        expire_at: None,
+    }
+}
+
+pub(crate) fn write(checks: Vec<Check>, mutations: Vec<Mutation>) -> AtomicWrite {
+    AtomicWrite {
+        checks,
+        mutations,
+        enqueues: vec![],
+    }
+}
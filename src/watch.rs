@@ -0,0 +1,207 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use denokv_proto::{KvEntry, Versionstamp, WatchKeyOutput};
+use futures::{future, stream, stream::BoxStream};
+use tokio::sync::{watch, Notify};
+
+use crate::{now_millis, read_store, LmdbDatabase, LmdbError, StoreSlot, WATCH_POLL_INTERVAL};
+
+type Senders = HashMap<Vec<u8>, watch::Sender<()>>;
+
+/// Watched keys, grouped by keyspace.
+#[derive(Default)]
+pub(crate) struct WatchHub {
+    senders: Mutex<HashMap<usize, Senders>>,
+}
+
+impl WatchHub {
+    fn subscribe(&self, keyspace: usize, key: &[u8]) -> watch::Receiver<()> {
+        let mut senders = self.senders.lock().unwrap();
+        senders
+            .entry(keyspace)
+            .or_default()
+            .entry(key.to_vec())
+            .or_insert_with(|| watch::channel(()).0)
+            .subscribe()
+    }
+
+    pub(crate) fn close(&self, keyspace: usize) {
+        self.senders.lock().unwrap().remove(&keyspace);
+    }
+
+    pub(crate) fn notify<'a>(&self, keyspace: usize, keys: impl IntoIterator<Item = &'a [u8]>) {
+        let mut senders = self.senders.lock().unwrap();
+        let Some(senders) = senders.get_mut(&keyspace) else {
+            return;
+        };
+        for key in keys {
+            if let Some(sender) = senders.get(key) {
+                if sender.send(()).is_err() {
+                    senders.remove(key);
+                }
+            }
+        }
+    }
+
+    fn notify_all(&self) {
+        for senders in self.senders.lock().unwrap().values_mut() {
+            senders.retain(|_, sender| sender.send(()).is_ok());
+        }
+    }
+}
+
+/// Wakes every watcher and queue consumer whenever the commit version moves,
+/// so that commits made by other processes sharing the env are seen too.
+/// Watchers re-read their keys and only yield if a versionstamp actually
+/// changed.
+#[derive(Default)]
+pub(crate) struct CommitPoller {
+    stop: Mutex<Option<mpsc::Sender<()>>>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl CommitPoller {
+    pub(crate) fn spawn(
+        store: Arc<StoreSlot>,
+        watchers: Arc<WatchHub>,
+        queue_waker: Arc<Notify>,
+    ) -> CommitPoller {
+        let mut seen = committed_version(&store).ok();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(WATCH_POLL_INTERVAL) {
+                match committed_version(&store) {
+                    Ok(version) if seen == Some(version) => {}
+                    Ok(version) => {
+                        if seen.is_some() {
+                            watchers.notify_all();
+                            queue_waker.notify_waiters();
+                        }
+                        seen = Some(version);
+                    }
+                    // The watchers' own reads reopen the env if another
+                    // process grew the map.
+                    Err(_) => watchers.notify_all(),
+                }
+            }
+        });
+        CommitPoller {
+            stop: Mutex::new(Some(stop)),
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    pub(crate) fn stop(&self) {
+        self.stop.lock().unwrap().take();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for CommitPoller {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn committed_version(store: &StoreSlot) -> Result<u64, LmdbError> {
+    Ok(read_store(store, 0)?.committed_version()?)
+}
+
+struct WatchState {
+    db: LmdbDatabase,
+    keys: Vec<Vec<u8>>,
+    receivers: Vec<watch::Receiver<()>>,
+    versionstamps: Option<Vec<Option<Versionstamp>>>,
+}
+
+impl LmdbDatabase {
+    pub(crate) fn watch_keys(
+        &self,
+        keys: Vec<Vec<u8>>,
+    ) -> BoxStream<'static, Result<Vec<WatchKeyOutput>, LmdbError>> {
+        let receivers = keys
+            .iter()
+            .map(|key| self.watchers.subscribe(self.keyspace, key))
+            .collect();
+        let state = WatchState {
+            db: self.clone(),
+            keys,
+            receivers,
+            versionstamps: None,
+        };
+
+        Box::pin(stream::try_unfold(state, |mut state| async move {
+            loop {
+                if state.versionstamps.is_some() {
+                    if state.receivers.is_empty() {
+                        return Ok(None);
+                    }
+                    let changed = state
+                        .receivers
+                        .iter_mut()
+                        .map(|receiver| Box::pin(receiver.changed()));
+                    if future::select_all(changed).await.0.is_err() {
+                        return Ok(None);
+                    }
+                }
+                for receiver in &mut state.receivers {
+                    receiver.borrow_and_update();
+                }
+
+                let (db, keys) = (state.db.clone(), state.keys.clone());
+                let entries = tokio::task::spawn_blocking(move || db.read_watched(&keys)).await??;
+                let versionstamps = entries
+                    .iter()
+                    .map(|entry| entry.as_ref().map(|e| e.versionstamp))
+                    .collect::<Vec<_>>();
+
+                let previous = state.versionstamps.replace(versionstamps.clone());
+                let outputs = match previous {
+                    None => entries
+                        .into_iter()
+                        .map(|entry| WatchKeyOutput::Changed { entry })
+                        .collect(),
+                    Some(previous) if previous != versionstamps => entries
+                        .into_iter()
+                        .zip(previous)
+                        .map(|(entry, previous)| {
+                            if entry.as_ref().map(|e| e.versionstamp) == previous {
+                                WatchKeyOutput::Unchanged
+                            } else {
+                                WatchKeyOutput::Changed { entry }
+                            }
+                        })
+                        .collect(),
+                    Some(_) => continue,
+                };
+                return Ok(Some((outputs, state)));
+            }
+        }))
+    }
+
+    fn read_watched(&self, keys: &[Vec<u8>]) -> Result<Vec<Option<KvEntry>>, LmdbError> {
+        self.with_store(|store| {
+            let txn = store.env.read_txn()?;
+            let now = now_millis();
+            keys.iter()
+                .map(|key| {
+                    let entry = store.get_live(&txn, key, now)?.map(|v| KvEntry {
+                        key: key.clone(),
+                        value: v.value.to_owned(),
+                        versionstamp: v.versionstamp,
+                    });
+                    Ok(entry)
+                })
+                .collect()
+        })
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    sync::{mpsc, Mutex},
+    thread,
+};
+
+use denokv_proto::{AtomicWrite, CommitResult};
+
+use crate::{LmdbDatabase, LmdbError};
+
+/// Upper bound on the number of atomic writes packed into one transaction.
+const MAX_BATCH_SIZE: usize = 256;
+
+struct Commit {
+    db: LmdbDatabase,
+    queue: String,
+    write: AtomicWrite,
+    reply: oneshot::Sender<Result<CommitResult, LmdbError>>,
+}
+
+enum Job {
+    Commit(Commit),
+    Task(Box<dyn FnOnce() + Send>),
+}
+
+/// A dedicated thread that runs every write transaction, so that commits and
+/// their fsyncs never block an async executor. Atomic writes that queue up
+/// while a transaction commits are grouped into the next one.
+#[derive(Default)]
+pub(crate) struct Writer {
+    jobs: Mutex<Option<mpsc::Sender<Job>>>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl Writer {
+    pub(crate) fn spawn() -> Writer {
+        let (jobs, queued) = mpsc::channel::<Job>();
+        let thread = thread::spawn(move || {
+            let mut next = None;
+            loop {
+                let job = match next.take() {
+                    Some(job) => job,
+                    None => match queued.recv() {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    },
+                };
+                match job {
+                    Job::Task(task) => task(),
+                    Job::Commit(commit) => {
+                        let mut batch = vec![commit];
+                        while batch.len() < MAX_BATCH_SIZE {
+                            match queued.try_recv() {
+                                Ok(Job::Commit(commit)) => batch.push(commit),
+                                Ok(job) => {
+                                    next = Some(job);
+                                    break;
+                                }
+                                Err(_) => break,
+                            }
+                        }
+                        commit_batch(batch);
+                    }
+                }
+            }
+        });
+        Writer {
+            jobs: Mutex::new(Some(jobs)),
+            thread: Mutex::new(Some(thread)),
+        }
+    }
+
+    fn send(&self, job: Job) -> Result<(), LmdbError> {
+        let jobs = self.jobs.lock().unwrap();
+        let jobs = jobs.as_ref().ok_or(LmdbError::Closed)?;
+        jobs.send(job).map_err(|_| LmdbError::Closed)
+    }
+
+    pub(crate) async fn commit(
+        &self,
+        db: LmdbDatabase,
+        queue: String,
+        write: AtomicWrite,
+    ) -> Result<CommitResult, LmdbError> {
+        let (reply, result) = oneshot::channel();
+        self.send(Job::Commit(Commit {
+            db,
+            queue,
+            write,
+            reply,
+        }))?;
+        result.await.map_err(|_| LmdbError::Closed)?
+    }
+
+    pub(crate) async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> Result<T, LmdbError> + Send + 'static,
+    ) -> Result<T, LmdbError> {
+        let (reply, result) = oneshot::channel();
+        self.send(Job::Task(Box::new(move || {
+            let _ = reply.send(job());
+        })))?;
+        result.await.map_err(|_| LmdbError::Closed)?
+    }
+
+    /// Stop accepting jobs and wait for the queued ones to finish.
+    pub(crate) fn stop(&self) {
+        self.jobs.lock().unwrap().take();
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            // The last handle to the database may be dropped by a job running
+            // on the writer thread itself, which then exits on its own.
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn commit_batch(batch: Vec<Commit>) {
+    // Writes to a keyspace that was closed while they were queued fail on
+    // their own rather than with the rest of the batch.
+    let (batch, closed): (Vec<_>, Vec<_>) = batch
+        .into_iter()
+        .partition(|commit| commit.db.ensure_open().is_ok());
+    for commit in closed {
+        let _ = commit.reply.send(Err(LmdbError::Closed));
+    }
+    let Some(db) = batch.first().map(|commit| commit.db.clone()) else {
+        return;
+    };
+    let (writes, replies): (Vec<_>, Vec<_>) = batch
+        .into_iter()
+        .map(|commit| {
+            let keyspace = commit.db.keyspace;
+            ((keyspace, commit.queue, commit.write), commit.reply)
+        })
+        .unzip();
+    for (reply, result) in replies.into_iter().zip(db.commit_batch(&writes)) {
+        let _ = reply.send(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use denokv_proto::{Check, KvValue, Mutation, MutationKind};
+
+    use super::*;
+    use crate::{now_millis, test_util, DEFAULT_QUEUE};
+
+    fn set(key: &[u8]) -> Mutation {
+        test_util::set(key, key)
+    }
+
+    /// A write to the default keyspace and queue, as the writer passes it on.
+    fn write(checks: Vec<Check>, mutations: Vec<Mutation>) -> (usize, String, AtomicWrite) {
+        (
+            0,
+            DEFAULT_QUEUE.to_owned(),
+            test_util::write(checks, mutations),
+        )
+    }
+
+    fn exists(db: &LmdbDatabase, key: &[u8]) -> bool {
+        let store = db.store().unwrap();
+        let txn = store.env.read_txn().unwrap();
+        store.get_live(&txn, key, now_millis()).unwrap().is_some()
+    }
+
+    #[test]
+    fn failed_writes_in_a_batch_only_roll_back_themselves() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = LmdbDatabase::new(dir.path()).unwrap();
+
+        let max = Mutation {
+            key: b"a".to_vec(),
+            kind: MutationKind::Max(KvValue::U64(1)),
+            expire_at: None,
+        };
+        let conflict = Check {
+            key: b"a".to_vec(),
+            versionstamp: None,
+        };
+        let results = db.commit_batch(&[
+            write(vec![], vec![set(b"a")]),
+            // Sees the first write, so its check fails.
+            write(vec![conflict], vec![set(b"checked")]),
+            // Fails after its first mutation has been applied.
+            write(vec![], vec![set(b"partial"), max]),
+            write(vec![], vec![set(b"b")]),
+        ]);
+
+        assert_eq!(results.len(), 4);
+        assert!(matches!(results[1], Err(LmdbError::CheckFailed)));
+        assert!(matches!(results[2], Err(LmdbError::NonU64Value(_))));
+        let first = results[0].as_ref().unwrap().versionstamp;
+        let last = results[3].as_ref().unwrap().versionstamp;
+        // One commit version for the batch, then each write's index in it.
+        assert_eq!(first[..8], last[..8]);
+        assert_eq!((&first[8..], &last[8..]), (&[0, 0][..], &[0, 3][..]));
+
+        assert!(exists(&db, b"a") && exists(&db, b"b"));
+        assert!(!exists(&db, b"checked") && !exists(&db, b"partial"));
+
+        let next = db.commit_batch(&[write(vec![], vec![set(b"c")])]);
+        assert!(next[0].as_ref().unwrap().versionstamp > last);
+        db.close();
+    }
+}
@@ -0,0 +1,83 @@
+//! Helpers shared by the integration tests. Each test binary uses only some
+//! of them.
+#![allow(dead_code)]
+
+use std::num::NonZeroU32;
+
+use chrono::Utc;
+use denokv_lmdb::{LmdbDatabase, QueueMessageInfo};
+use denokv_proto::{
+    AtomicWrite, Check, Consistency, Enqueue, KvEntry, KvValue, Mutation, MutationKind, ReadRange,
+    SnapshotReadOptions,
+};
+use tempfile::TempDir;
+
+pub fn open() -> (TempDir, LmdbDatabase) {
+    let dir = tempfile::tempdir().unwrap();
+    let db = LmdbDatabase::new(dir.path()).unwrap();
+    (dir, db)
+}
+
+pub fn write(checks: Vec<Check>, mutations: Vec<(&[u8], MutationKind)>) -> AtomicWrite {
+    AtomicWrite {
+        checks,
+        mutations: mutations
+            .into_iter()
+            .map(|(key, kind)| Mutation {
+                key: key.to_vec(),
+                kind,
+                expire_at: None,
+            })
+            .collect(),
+        enqueues: vec![],
+    }
+}
+
+pub fn set(key: &[u8], n: u64) -> AtomicWrite {
+    write(vec![], vec![(key, MutationKind::Set(KvValue::U64(n)))])
+}
+
+pub async fn get(db: &LmdbDatabase, key: &[u8]) -> Option<KvEntry> {
+    let range = ReadRange {
+        start: key.to_vec(),
+        end: [key, &[0]].concat(),
+        limit: NonZeroU32::new(1).unwrap(),
+        reverse: false,
+    };
+    let options = SnapshotReadOptions {
+        consistency: Consistency::Strong,
+    };
+    let mut outputs = db.snapshot_read(vec![range], options).await.unwrap();
+    outputs.remove(0).entries.pop()
+}
+
+pub fn as_u64(entry: &KvEntry) -> u64 {
+    match entry.value {
+        KvValue::U64(n) => n,
+        _ => panic!("not a U64 value"),
+    }
+}
+
+/// A message due after `delay`, with no undelivered keys or backoff.
+pub fn message(payload: &[u8], delay: chrono::Duration) -> Enqueue {
+    Enqueue {
+        payload: payload.to_vec(),
+        deadline: Utc::now() + delay,
+        keys_if_undelivered: vec![],
+        backoff_schedule: None,
+    }
+}
+
+pub async fn enqueue(db: &LmdbDatabase, queue: &str, payload: &[u8], delay: chrono::Duration) {
+    let write = AtomicWrite {
+        enqueues: vec![message(payload, delay)],
+        ..write(vec![], vec![])
+    };
+    db.atomic_write_to_queue(write, queue).await.unwrap();
+}
+
+pub async fn only_message(db: &LmdbDatabase) -> QueueMessageInfo {
+    let mut messages = db.queue_messages().await.unwrap();
+    assert_eq!(messages.len(), 1);
+    messages.remove(0)
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use common::{as_u64, get, open, write};
+use denokv_lmdb::LmdbError;
+use denokv_proto::{Check, KvValue, MutationKind};
+use futures::future;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn only_one_of_conflicting_writes_commits() {
+    let (_dir, db) = open();
+    let writes = (0..32u64).map(|i| {
+        let db = db.clone();
+        tokio::spawn(async move {
+            let check = Check {
+                key: b"slot".to_vec(),
+                versionstamp: None,
+            };
+            let set = MutationKind::Set(KvValue::U64(i));
+            let result = db.atomic_write(write(vec![check], vec![(b"slot", set)]));
+            (i, result.await.unwrap())
+        })
+    });
+    let results = future::join_all(writes).await;
+
+    let committed = results
+        .into_iter()
+        .filter_map(|result| {
+            let (i, commit) = result.unwrap();
+            commit.map(|commit| (i, commit.versionstamp))
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(committed.len(), 1);
+    let entry = get(&db, b"slot").await.unwrap();
+    assert_eq!(as_u64(&entry), committed[0].0);
+    assert_eq!(entry.versionstamp, committed[0].1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn checked_increments_are_not_lost() {
+    let (_dir, db) = open();
+    let tasks = (0..16).map(|_| {
+        let db = db.clone();
+        tokio::spawn(async move {
+            let mut versionstamps = Vec::new();
+            for _ in 0..10 {
+                loop {
+                    let current = get(&db, b"counter").await;
+                    let check = Check {
+                        key: b"counter".to_vec(),
+                        versionstamp: current.as_ref().map(|entry| entry.versionstamp),
+                    };
+                    let next = current.as_ref().map_or(0, as_u64) + 1;
+                    let set = MutationKind::Set(KvValue::U64(next));
+                    let commit = db.atomic_write(write(vec![check], vec![(b"counter", set)]));
+                    if let Some(commit) = commit.await.unwrap() {
+                        versionstamps.push(commit.versionstamp);
+                        break;
+                    }
+                }
+            }
+            versionstamps
+        })
+    });
+    let per_task = future::join_all(tasks).await;
+
+    let mut all = Vec::new();
+    for versionstamps in per_task {
+        let versionstamps = versionstamps.unwrap();
+        // Each task's own writes are committed in the order it made them.
+        assert!(versionstamps.windows(2).all(|pair| pair[0] < pair[1]));
+        all.extend(versionstamps);
+    }
+    all.sort();
+    all.dedup();
+    assert_eq!(all.len(), 160);
+    assert_eq!(as_u64(&get(&db, b"counter").await.unwrap()), 160);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failed_write_leaves_concurrent_writes_committed() {
+    let (_dir, db) = open();
+    let text = MutationKind::Set(KvValue::Bytes(b"text".to_vec()));
+    db.atomic_write(write(vec![], vec![(b"text", text)]))
+        .await
+        .unwrap();
+
+    let keys = (0..20u8).map(|i| vec![b'k', i]).collect::<Vec<_>>();
+    let writes = keys.iter().enumerate().map(|(i, key)| {
+        let mut mutations = vec![(key.as_slice(), MutationKind::Set(KvValue::U64(1)))];
+        if i == 10 {
+            // A U64 mutation on a bytes value fails the whole write.
+            mutations.push((b"text", MutationKind::Max(KvValue::U64(1))));
+        }
+        db.atomic_write(write(vec![], mutations))
+    });
+    let results = future::join_all(writes).await;
+
+    for (i, (key, result)) in keys.iter().zip(results).enumerate() {
+        if i == 10 {
+            assert!(matches!(result, Err(LmdbError::NonU64Value(_))));
+            assert!(get(&db, key).await.is_none());
+        } else {
+            let versionstamp = result.unwrap().unwrap().versionstamp;
+            assert_eq!(get(&db, key).await.unwrap().versionstamp, versionstamp);
+        }
+    }
+}
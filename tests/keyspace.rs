@@ -0,0 +1,106 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{as_u64, enqueue, get, open, set};
+use denokv_lmdb::{LmdbDatabase, LmdbError, DEFAULT_QUEUE};
+use heed::{types::ByteSlice, Database, EnvOpenOptions};
+
+#[test]
+fn rejects_invalid_names() {
+    let (_dir, db) = open();
+    for name in ["", "a/b", "/", "nul\0"] {
+        let result = db.open_keyspace(name);
+        assert!(
+            matches!(&result, Err(LmdbError::InvalidKeyspaceName(n)) if n == name),
+            "{name:?}"
+        );
+    }
+    db.open_keyspace("tenant-1").unwrap().close();
+    db.close();
+}
+
+#[tokio::test]
+async fn opening_a_keyspace_twice_shares_the_handle() {
+    let (_dir, db) = open();
+    let first = db.open_keyspace("t").unwrap();
+    let second = db.open_keyspace("t").unwrap();
+    second.close();
+    assert!(matches!(
+        first.atomic_write(set(b"k", 1)).await,
+        Err(LmdbError::Closed)
+    ));
+
+    // Once closed, opening it again gives a new handle.
+    let third = db.open_keyspace("t").unwrap();
+    third.atomic_write(set(b"k", 1)).await.unwrap();
+    db.close();
+    third.atomic_write(set(b"k", 2)).await.unwrap();
+    third.close();
+}
+
+#[tokio::test]
+async fn keeps_keys_and_queues_apart() {
+    let (_dir, db) = open();
+    let tenant = db.open_keyspace("tenant").unwrap();
+    db.atomic_write(set(b"k", 1)).await.unwrap();
+    tenant.atomic_write(set(b"k", 2)).await.unwrap();
+    enqueue(&tenant, DEFAULT_QUEUE, b"job", chrono::Duration::zero()).await;
+
+    assert_eq!(get(&db, b"k").await.as_ref().map(as_u64), Some(1));
+    assert_eq!(get(&tenant, b"k").await.as_ref().map(as_u64), Some(2));
+    assert!(db.queue_messages().await.unwrap().is_empty());
+    let dequeued = tokio::time::timeout(Duration::from_millis(200), db.dequeue_next_message());
+    assert!(dequeued.await.is_err());
+    let mut handle = tenant.dequeue_next_message().await.unwrap().unwrap();
+    assert_eq!(handle.take_payload().await.unwrap(), b"job");
+    handle.finish(true).await.unwrap();
+    tenant.close();
+    db.close();
+}
+
+#[tokio::test]
+async fn reopens_with_keyspaces() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = LmdbDatabase::new(dir.path()).unwrap();
+    let tenant = db.open_keyspace("tenant").unwrap();
+    tenant.atomic_write(set(b"k", 2)).await.unwrap();
+    tenant.close();
+    db.close();
+    drop((db, tenant));
+
+    let db = LmdbDatabase::new(dir.path()).unwrap();
+    assert!(get(&db, b"k").await.is_none());
+    let tenant = db.open_keyspace("tenant").unwrap();
+    assert_eq!(get(&tenant, b"k").await.as_ref().map(as_u64), Some(2));
+    tenant.close();
+    db.close();
+}
+
+#[test]
+fn too_many_keyspaces_leaves_no_databases_behind() {
+    let dir = tempfile::tempdir().unwrap();
+    // The minimum leaves room for only part of one more keyspace.
+    let db = LmdbDatabase::builder().max_dbs(0).open(dir.path()).unwrap();
+    assert!(matches!(
+        db.open_keyspace("tenant"),
+        Err(LmdbError::TooManyKeyspaces)
+    ));
+    db.close();
+    drop(db);
+
+    let env = EnvOpenOptions::new().open(dir.path()).unwrap();
+    let main: Database<ByteSlice, ByteSlice> = env.open_database(None).unwrap().unwrap();
+    let txn = env.read_txn().unwrap();
+    let names = main
+        .iter(&txn)
+        .unwrap()
+        .map(|entry| String::from_utf8_lossy(entry.unwrap().0).into_owned())
+        .collect::<Vec<_>>();
+    assert!(
+        names.iter().all(|name| !name.starts_with("tenant/")),
+        "{names:?}"
+    );
+    drop(txn);
+    env.prepare_for_closing().wait();
+}
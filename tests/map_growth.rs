@@ -0,0 +1,38 @@
+mod common;
+
+use common::{get, write};
+use denokv_lmdb::{LmdbDatabase, LmdbError};
+use denokv_proto::{KvValue, MutationKind};
+
+const PAGE: usize = 4096;
+
+#[tokio::test]
+async fn grows_the_map_up_to_its_cap() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = LmdbDatabase::builder()
+        .map_size(64 * PAGE)
+        .max_map_size(1024 * PAGE)
+        .open(dir.path())
+        .unwrap();
+
+    let value = vec![7; 60_000];
+    let mut written = 0;
+    let error = loop {
+        let key = (written as u32).to_be_bytes();
+        let set = MutationKind::Set(KvValue::Bytes(value.clone()));
+        match db.atomic_write(write(vec![], vec![(&key, set)])).await {
+            Ok(_) => written += 1,
+            Err(e) => break e,
+        }
+        assert!(written < 1000, "the map never filled up");
+    };
+    assert!(matches!(error, LmdbError::MapFull), "{error}");
+    // Far more than the initial map holds, and no more than the cap.
+    assert!(written * value.len() > 64 * PAGE * 4);
+    assert!(written * value.len() < 1024 * PAGE);
+
+    for i in 0..written as u32 {
+        assert!(get(&db, &i.to_be_bytes()).await.is_some());
+    }
+    db.close();
+}
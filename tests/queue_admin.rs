@@ -0,0 +1,151 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{enqueue, only_message, open};
+use denokv_lmdb::{LmdbError, QueueMessageState, DEFAULT_QUEUE};
+
+#[tokio::test]
+async fn lists_messages_in_each_state() {
+    let (_dir, db) = open();
+    enqueue(&db, DEFAULT_QUEUE, b"leased", chrono::Duration::zero()).await;
+    let handle = db.dequeue_next_message().await.unwrap().unwrap();
+    enqueue(&db, DEFAULT_QUEUE, b"pending", chrono::Duration::zero()).await;
+    enqueue(&db, "jobs", b"scheduled", chrono::Duration::hours(1)).await;
+
+    let messages = db.queue_messages().await.unwrap();
+    let summary = messages
+        .iter()
+        .map(|m| (m.payload.as_slice(), m.queue.as_str(), m.state))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        summary,
+        [
+            (&b"leased"[..], DEFAULT_QUEUE, QueueMessageState::InFlight),
+            (&b"pending"[..], DEFAULT_QUEUE, QueueMessageState::Pending),
+            (&b"scheduled"[..], "jobs", QueueMessageState::Scheduled),
+        ]
+    );
+    assert_eq!(messages[0].id, handle.id());
+}
+
+#[tokio::test]
+async fn requeues_scheduled_message() {
+    let (_dir, db) = open();
+    enqueue(&db, DEFAULT_QUEUE, b"later", chrono::Duration::hours(1)).await;
+    let message = only_message(&db).await;
+    assert_eq!(message.state, QueueMessageState::Scheduled);
+
+    assert!(db.requeue_message(message.id).await.unwrap());
+    assert_eq!(only_message(&db).await.state, QueueMessageState::Pending);
+    let mut handle = tokio::time::timeout(Duration::from_secs(5), db.dequeue_next_message())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(handle.take_payload().await.unwrap(), b"later");
+}
+
+#[tokio::test]
+async fn requeues_leased_message() {
+    let (_dir, db) = open();
+    enqueue(&db, DEFAULT_QUEUE, b"work", chrono::Duration::zero()).await;
+    let handle = db.dequeue_next_message().await.unwrap().unwrap();
+    assert_eq!(only_message(&db).await.state, QueueMessageState::InFlight);
+
+    assert!(db.requeue_message(handle.id()).await.unwrap());
+    let message = only_message(&db).await;
+    assert_eq!(message.state, QueueMessageState::Pending);
+    assert_eq!(message.id, handle.id());
+
+    // The old lease is gone, so finishing through it does nothing.
+    handle.finish(true).await.unwrap();
+    assert_eq!(only_message(&db).await.state, QueueMessageState::Pending);
+}
+
+#[tokio::test]
+async fn deletes_message_in_each_state() {
+    let (_dir, db) = open();
+    enqueue(&db, DEFAULT_QUEUE, b"leased", chrono::Duration::zero()).await;
+    let handle = db.dequeue_next_message().await.unwrap().unwrap();
+    enqueue(&db, DEFAULT_QUEUE, b"pending", chrono::Duration::zero()).await;
+    enqueue(&db, DEFAULT_QUEUE, b"scheduled", chrono::Duration::hours(1)).await;
+
+    for message in db.queue_messages().await.unwrap() {
+        assert!(db.delete_message(message.id).await.unwrap());
+        assert!(!db.delete_message(message.id).await.unwrap());
+        assert!(!db.requeue_message(message.id).await.unwrap());
+    }
+    assert!(db.queue_messages().await.unwrap().is_empty());
+    handle.finish(false).await.unwrap();
+    assert!(db.queue_messages().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn manages_messages_in_named_queue() {
+    let (_dir, db) = open();
+    enqueue(&db, "jobs", b"first", chrono::Duration::hours(1)).await;
+    enqueue(&db, "jobs", b"second", chrono::Duration::hours(2)).await;
+    let messages = db.queue_messages().await.unwrap();
+    assert!(messages.iter().all(|m| m.queue == "jobs"));
+
+    assert!(db.delete_message(messages[0].id).await.unwrap());
+    assert!(db.requeue_message(messages[1].id).await.unwrap());
+    let message = only_message(&db).await;
+    assert_eq!(message.payload, b"second");
+    assert_eq!(message.state, QueueMessageState::Pending);
+
+    let mut handle =
+        tokio::time::timeout(Duration::from_secs(5), db.dequeue_next_message_from("jobs"))
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    assert_eq!(handle.take_payload().await.unwrap(), b"second");
+    handle.finish(true).await.unwrap();
+    assert!(db.queue_messages().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn unknown_message_is_not_found() {
+    let (_dir, db) = open();
+    enqueue(&db, DEFAULT_QUEUE, b"work", chrono::Duration::zero()).await;
+    assert!(!db.requeue_message([0xff; 12]).await.unwrap());
+    assert!(!db.delete_message([0xff; 12]).await.unwrap());
+    assert_eq!(only_message(&db).await.payload, b"work");
+}
+
+#[tokio::test]
+async fn purges_only_the_named_queue() {
+    let (_dir, db) = open();
+    enqueue(&db, DEFAULT_QUEUE, b"kept", chrono::Duration::zero()).await;
+    enqueue(&db, "jobs", b"leased", chrono::Duration::zero()).await;
+    let handle = db.dequeue_next_message_from("jobs").await.unwrap().unwrap();
+    enqueue(&db, "jobs", b"scheduled", chrono::Duration::hours(1)).await;
+
+    assert_eq!(db.purge_queue("jobs").await.unwrap(), 2);
+    assert_eq!(only_message(&db).await.payload, b"kept");
+    handle.finish(false).await.unwrap();
+    assert_eq!(only_message(&db).await.payload, b"kept");
+
+    let too_long = "q".repeat(256);
+    assert!(matches!(
+        db.purge_queue(&too_long).await,
+        Err(LmdbError::LimitExceeded(_))
+    ));
+}
+
+#[tokio::test]
+async fn lists_queued_messages_by_deadline_across_queues() {
+    let (_dir, db) = open();
+    enqueue(&db, "jobs", b"third", chrono::Duration::hours(2)).await;
+    enqueue(&db, DEFAULT_QUEUE, b"second", chrono::Duration::hours(1)).await;
+    enqueue(&db, "a-longer-name", b"first", chrono::Duration::zero()).await;
+
+    let messages = db.queue_messages().await.unwrap();
+    let payloads = messages
+        .iter()
+        .map(|m| m.payload.as_slice())
+        .collect::<Vec<_>>();
+    assert_eq!(payloads, [&b"first"[..], b"second", b"third"]);
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use std::{path::Path, time::Duration};
+
+use common::{get, message, only_message, write};
+use denokv_lmdb::{LmdbDatabase, LmdbMessageHandle, QueueMessageState};
+use denokv_proto::{AtomicWrite, Enqueue};
+
+const LEASE: Duration = Duration::from_millis(100);
+
+fn open(path: &Path, lease: Duration) -> LmdbDatabase {
+    LmdbDatabase::builder()
+        .message_lease(lease)
+        .open(path)
+        .unwrap()
+}
+
+/// Enqueues a message that is written to `dead` once `backoff_schedule` is
+/// used up.
+async fn enqueue(db: &LmdbDatabase, backoff_schedule: Vec<u32>) {
+    let enqueue = Enqueue {
+        keys_if_undelivered: vec![b"dead".to_vec()],
+        backoff_schedule: Some(backoff_schedule),
+        ..message(b"work", chrono::Duration::zero())
+    };
+    let write = AtomicWrite {
+        enqueues: vec![enqueue],
+        ..write(vec![], vec![])
+    };
+    db.atomic_write(write).await.unwrap();
+}
+
+async fn dequeue(db: &LmdbDatabase) -> LmdbMessageHandle {
+    tokio::time::timeout(Duration::from_secs(5), db.dequeue_next_message())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn expired_lease_is_retried_then_dead_lettered() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = open(dir.path(), LEASE);
+    enqueue(&db, vec![0]).await;
+
+    let first = dequeue(&db).await;
+    // Never finished, so the sweeper requeues it once the lease expires.
+    let second = dequeue(&db).await;
+    assert_eq!(second.id(), first.id());
+    let message = only_message(&db).await;
+    assert_eq!(message.state, QueueMessageState::InFlight);
+    assert_eq!(message.retries, 1);
+    assert!(message.backoff_schedule.is_empty());
+
+    // Out of retries: the next expiry dead-letters the message.
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while get(&db, b"dead").await.is_none() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert!(db.queue_messages().await.unwrap().is_empty());
+    db.close();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn close_returns_leased_message_without_a_retry() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = open(dir.path(), Duration::from_secs(3600));
+    enqueue(&db, vec![]).await;
+    let handle = dequeue(&db).await;
+    db.close();
+
+    let db = open(dir.path(), Duration::from_secs(3600));
+    let message = only_message(&db).await;
+    assert_eq!(message.id, handle.id());
+    assert_eq!(message.state, QueueMessageState::Pending);
+    assert_eq!(message.retries, 0);
+    assert!(get(&db, b"dead").await.is_none());
+    assert_eq!(dequeue(&db).await.id(), handle.id());
+    db.close();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn opening_again_leaves_live_leases_alone() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = open(dir.path(), Duration::from_secs(3600));
+    enqueue(&db, vec![]).await;
+    let handle = dequeue(&db).await;
+
+    // Stands in for another process opening the same env.
+    let other = open(dir.path(), Duration::from_secs(3600));
+    assert_eq!(
+        only_message(&other).await.state,
+        QueueMessageState::InFlight
+    );
+    let stolen = tokio::time::timeout(Duration::from_millis(500), other.dequeue_next_message());
+    assert!(stolen.await.is_err());
+    drop(other);
+
+    handle.finish(true).await.unwrap();
+    assert!(db.queue_messages().await.unwrap().is_empty());
+    db.close();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn crashed_consumer_lease_expires_after_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = open(dir.path(), LEASE);
+    enqueue(&db, vec![0]).await;
+    let id = dequeue(&db).await.id();
+    // Dropped without closing, as if the process had crashed.
+    drop(db);
+
+    let db = open(dir.path(), LEASE);
+    let redelivered = dequeue(&db).await;
+    assert_eq!(redelivered.id(), id);
+    assert_eq!(only_message(&db).await.retries, 1);
+    redelivered.finish(true).await.unwrap();
+    assert!(db.queue_messages().await.unwrap().is_empty());
+    assert!(get(&db, b"dead").await.is_none());
+    db.close();
+}